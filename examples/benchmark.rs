@@ -0,0 +1,37 @@
+//! Compiles generated templates of growing size to show that lexing and
+//! parsing take linear time, the time per byte should stay about the same.
+//!
+//! Run with `cargo run --release --example benchmark`.
+
+extern crate milstian_templates;
+
+use milstian_templates::Environment;
+use std::sync::Arc;
+use std::time::Instant;
+
+const SECTION: &str = "<p>{{ user.name | upper }} said \"{{ 'hello' }}\"</p>\n{% if count > 1 && !hidden %}{% foreach (items as key => item) %}<li>{{ key }}: {{ item * 2.5 }}</li>{% endforeach %}{% endif %}\n";
+
+fn main() {
+    let environment = Arc::new(Environment::new());
+    println!(
+        "{:>10} {:>14} {:>12}",
+        "megabytes", "milliseconds", "ns per byte"
+    );
+    for megabytes in &[1, 2, 4, 8] {
+        let size = megabytes * 1024 * 1024;
+        let mut form = String::with_capacity(size + SECTION.len());
+        while form.len() < size {
+            form.push_str(SECTION);
+        }
+        let start = Instant::now();
+        environment.compile(&form).unwrap();
+        let elapsed = start.elapsed();
+        let nanoseconds = elapsed.as_secs() as f64 * 1e9 + f64::from(elapsed.subsec_nanos());
+        println!(
+            "{:>10} {:>14.1} {:>12.1}",
+            megabytes,
+            nanoseconds / 1e6,
+            nanoseconds / form.len() as f64
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use DataType;
+use LexerPosition;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOperator {
+    Addition,
+    And,
+    Division,
+    Equals,
+    GreaterOrEqualThan,
+    GreaterThan,
+    LesserOrEqualThan,
+    LesserOrGreaterThan,
+    LesserThan,
+    Multiplication,
+    Or,
+    Subtraction,
+}
+
+/// Operators display as they are written in templates, like in error messages.
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            BinaryOperator::Addition => "+",
+            BinaryOperator::And => "&&",
+            BinaryOperator::Division => "/",
+            BinaryOperator::Equals => "==",
+            BinaryOperator::GreaterOrEqualThan => ">=",
+            BinaryOperator::GreaterThan => ">",
+            BinaryOperator::LesserOrEqualThan => "<=",
+            BinaryOperator::LesserOrGreaterThan => "<>",
+            BinaryOperator::LesserThan => "<",
+            BinaryOperator::Multiplication => "*",
+            BinaryOperator::Or => "||",
+            BinaryOperator::Subtraction => "-",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOperator {
+    Minus,
+    Negation,
+}
+
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            UnaryOperator::Minus => "-",
+            UnaryOperator::Negation => "!",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpressionKind {
+    Binary {
+        left: Box<Expression>,
+        operator: BinaryOperator,
+        operator_position: LexerPosition,
+        right: Box<Expression>,
+    },
+    Call {
+        name: String,
+        arguments: Vec<Expression>,
+    },
+    /// `subject | name` or `subject | name(arguments)`
+    Filter {
+        arguments: Vec<Expression>,
+        name: String,
+        name_position: LexerPosition,
+        subject: Box<Expression>,
+    },
+    /// `subject.name` or `subject[key]`, a member name is parsed as a string key
+    Index {
+        subject: Box<Expression>,
+        key: Box<Expression>,
+    },
+    Literal(DataType),
+    Unary {
+        operator: UnaryOperator,
+        operand: Box<Expression>,
+    },
+    Variable(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub position: LexerPosition,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Branch {
+    pub condition: Expression,
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StatementKind {
+    Assign {
+        name: String,
+        value: Expression,
+    },
+    Block(Vec<Statement>),
+    Echo(Expression),
+    Expression(Expression),
+    /// `{% extends "name" %}`, the template is rendered as its parent with blocks overridden
+    Extends(Expression),
+    ForEach {
+        subject: Expression,
+        key: Option<String>,
+        value: String,
+        statements: Vec<Statement>,
+    },
+    If {
+        branches: Vec<Branch>,
+        otherwise: Option<Vec<Statement>>,
+    },
+    /// `{% include "name" %}` with the current context, or `with` an explicit one
+    Include {
+        name: Expression,
+        context: Option<Expression>,
+    },
+    Increment {
+        name: String,
+        step: i64,
+    },
+    Inline(String),
+    /// `{% block name %}...{% endblock %}`, overridable by templates extending this one
+    NamedBlock {
+        name: String,
+        /// Shared with the block table of the program
+        statements: Arc<Vec<Statement>>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub position: LexerPosition,
+}
+
+/// A parsed template, with its blocks by name wherever they are nested, so
+/// renders extending it don't have to look for them.
+#[derive(Debug)]
+pub struct Program {
+    pub blocks: HashMap<String, Arc<Vec<Statement>>>,
+    pub statements: Vec<Statement>,
+}
+
+impl Program {
+    pub fn new(statements: Vec<Statement>) -> Program {
+        let mut blocks = HashMap::new();
+        collect_blocks(&statements, &mut blocks);
+        Program { blocks, statements }
+    }
+}
+
+fn collect_blocks(statements: &[Statement], blocks: &mut HashMap<String, Arc<Vec<Statement>>>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Block(statements) | StatementKind::ForEach { statements, .. } => {
+                collect_blocks(statements, blocks)
+            }
+            StatementKind::If {
+                branches,
+                otherwise,
+            } => {
+                for branch in branches {
+                    collect_blocks(&branch.statements, blocks);
+                }
+                if let Some(statements) = otherwise {
+                    collect_blocks(statements, blocks);
+                }
+            }
+            StatementKind::NamedBlock { name, statements } => {
+                blocks.insert(name.to_string(), statements.clone());
+                collect_blocks(statements, blocks);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use error::TemplateError;
+use std::fmt;
+
+const BLUE: &str = "\x1b[1;34m";
+const RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+/// Human-readable report of an error, in the style of a compiler: the message,
+/// the template and where in it the error is, and the offending lines with
+/// the source underlined by carets.
+///
+/// ```text
+/// error: Reference to undefined variable: missing
+///  --> partial.html:2:4
+///   |
+/// 2 | {{ missing }}
+///   |    ^^^^^^^
+/// ```
+#[derive(Debug)]
+pub struct Diagnostic<'a> {
+    colors: bool,
+    error: &'a TemplateError,
+    /// Form of the template the error is in, without it only the message and location are shown
+    source: Option<String>,
+}
+
+impl<'a> Diagnostic<'a> {
+    pub fn new(error: &'a TemplateError, source: Option<String>) -> Diagnostic<'a> {
+        Diagnostic {
+            colors: false,
+            error,
+            source,
+        }
+    }
+
+    /// Highlight the report with ANSI colors for a terminal, it is plain text by default.
+    pub fn set_colors(&mut self, colors: bool) {
+        self.colors = colors;
+    }
+
+    /// Text wrapped in `color` when colors are on.
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.colors {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+impl<'a> fmt::Display for Diagnostic<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if let TemplateError::Multiple { errors } = self.error {
+            // A report of its own for every error, separated by an empty line
+            for (index, error) in errors.iter().enumerate() {
+                if index > 0 {
+                    formatter.write_str("\n\n")?;
+                }
+                let mut diagnostic = Diagnostic::new(error, self.source.clone());
+                diagnostic.set_colors(self.colors);
+                write!(formatter, "{}", diagnostic)?;
+            }
+            return Ok(());
+        }
+        write!(
+            formatter,
+            "{} {}",
+            self.paint(RED, "error:"),
+            self.error.message()
+        )?;
+        let position = self.error.position();
+        let lines = match (&self.source, position) {
+            (Some(source), Some(position)) => {
+                source_lines(source, position.byte_start(), position.byte_end())
+            }
+            _ => Vec::new(),
+        };
+        let width = match lines.last() {
+            Some(line) => line.number.to_string().len(),
+            None => 0,
+        };
+        let gutter = " ".repeat(width);
+
+        // Location as template:line:column, where the column counts characters
+        let mut location: Vec<String> = Vec::new();
+        if let Some(template) = self.error.template() {
+            location.push(template.to_string());
+        }
+        match lines.first() {
+            Some(line) => {
+                location.push(line.number.to_string());
+                location.push((line.text[..line.start].chars().count() + 1).to_string());
+            }
+            None => {
+                if let Some(position) = position {
+                    location.push(position.line_start().to_string());
+                }
+            }
+        }
+        if !location.is_empty() {
+            write!(
+                formatter,
+                "\n{}{} {}",
+                gutter,
+                self.paint(BLUE, "-->"),
+                location.join(":")
+            )?;
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        write!(formatter, "\n{} {}", gutter, self.paint(BLUE, "|"))?;
+        for line in &lines {
+            write!(
+                formatter,
+                "\n{} {}",
+                self.paint(BLUE, &format!("{:>width$}", line.number, width = width)),
+                self.paint(BLUE, "|")
+            )?;
+            if !line.text.is_empty() {
+                write!(formatter, " {}", line.text)?;
+            }
+            // Tabs are kept in the indentation so the carets line up with the text
+            let indentation: String = line.text[..line.start]
+                .chars()
+                .map(|character| if character == '\t' { '\t' } else { ' ' })
+                .collect();
+            let length = line.text[line.start..line.end].chars().count().max(1);
+            write!(
+                formatter,
+                "\n{} {} {}{}",
+                gutter,
+                self.paint(BLUE, "|"),
+                indentation,
+                self.paint(RED, &"^".repeat(length))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of source with the underlined part, as byte offsets in the line.
+struct SourceLine<'a> {
+    end: usize,
+    number: usize,
+    start: usize,
+    text: &'a str,
+}
+
+/// Lines covered by the source from byte offset `start` to `end`.
+fn source_lines(source: &str, start: usize, end: usize) -> Vec<SourceLine<'_>> {
+    let start = boundary(source, start);
+    let end = boundary(source, end).max(start);
+    let mut line_start = match source[..start].rfind('\n') {
+        Some(index) => index + 1,
+        None => 0,
+    };
+    let mut number = source[..line_start].matches('\n').count() + 1;
+    let mut lines: Vec<SourceLine> = Vec::new();
+    loop {
+        let line_end = match source[line_start..].find('\n') {
+            Some(index) => line_start + index,
+            None => source.len(),
+        };
+        let text = source[line_start..line_end].trim_end_matches('\r');
+        lines.push(SourceLine {
+            end: end.min(line_start + text.len()).max(start) - line_start,
+            number,
+            start: start.max(line_start).min(line_start + text.len()) - line_start,
+            text,
+        });
+        // The line break ending the last line of a span doesn't start another one
+        if line_end + 1 >= end || line_end == source.len() {
+            break;
+        }
+        line_start = line_end + 1;
+        number += 1;
+    }
+    lines
+}
+
+/// Closest character boundary at or before byte offset `index`.
+fn boundary(source: &str, index: usize) -> usize {
+    let mut index = index.min(source.len());
+    while !source.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
@@ -0,0 +1,310 @@
+use std::error;
+use std::fmt;
+use std::io;
+use LexerPosition;
+
+/// Reason a template failed to compile or render.
+///
+/// Every kind carries the position of the offending source and the name of
+/// the template it is in, when the template was loaded by name, so callers can
+/// match on the kind and still report where it happened.
+#[derive(Debug)]
+pub enum TemplateError {
+    /// A native function or filter is undefined, got the wrong arguments or failed
+    Function {
+        message: String,
+        name: String,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+    /// Writing the output failed
+    Io {
+        error: io::Error,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+    /// The form contains text that isn't a token
+    Lex {
+        message: String,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+    /// A template referenced by name, such as a parent or an include, cannot be loaded
+    Load {
+        message: String,
+        name: String,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+    /// Several errors found in one pass, like every unrecognized character of
+    /// a form, there are always at least two
+    Multiple { errors: Vec<TemplateError> },
+    /// The tokens don't form a valid template
+    Parse {
+        message: String,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+    /// Rendering failed for another reason, such as a division by zero or
+    /// circular inheritance
+    Render {
+        message: String,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+    /// A value has the wrong type for an operator, a statement or the output
+    Type {
+        message: String,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+    /// A variable is neither defined in the template, the context nor the globals
+    UndefinedVariable {
+        name: String,
+        position: Option<Box<LexerPosition>>,
+        template: Option<String>,
+    },
+}
+
+impl TemplateError {
+    pub fn function(name: &str, message: String) -> TemplateError {
+        TemplateError::Function {
+            message,
+            name: name.to_string(),
+            position: None,
+            template: None,
+        }
+    }
+
+    pub fn io(error: io::Error) -> TemplateError {
+        TemplateError::Io {
+            error,
+            position: None,
+            template: None,
+        }
+    }
+
+    pub fn lex(message: String) -> TemplateError {
+        TemplateError::Lex {
+            message,
+            position: None,
+            template: None,
+        }
+    }
+
+    pub fn load(name: &str, message: String) -> TemplateError {
+        TemplateError::Load {
+            message,
+            name: name.to_string(),
+            position: None,
+            template: None,
+        }
+    }
+
+    /// Every error of `errors`, which must not be empty, or the error itself
+    /// when there is only one.
+    pub fn multiple(mut errors: Vec<TemplateError>) -> TemplateError {
+        if errors.len() == 1 {
+            errors.remove(0)
+        } else {
+            TemplateError::Multiple { errors }
+        }
+    }
+
+    pub fn parse(message: String) -> TemplateError {
+        TemplateError::Parse {
+            message,
+            position: None,
+            template: None,
+        }
+    }
+
+    pub fn render(message: String) -> TemplateError {
+        TemplateError::Render {
+            message,
+            position: None,
+            template: None,
+        }
+    }
+
+    pub fn type_mismatch(message: String) -> TemplateError {
+        TemplateError::Type {
+            message,
+            position: None,
+            template: None,
+        }
+    }
+
+    pub fn undefined_variable(name: &str) -> TemplateError {
+        TemplateError::UndefinedVariable {
+            name: name.to_string(),
+            position: None,
+            template: None,
+        }
+    }
+
+    /// The errors found, several for `Multiple` and otherwise only this one.
+    pub fn errors(&self) -> &[TemplateError] {
+        match self {
+            TemplateError::Multiple { errors } => errors,
+            _ => std::slice::from_ref(self),
+        }
+    }
+
+    /// Position of the offending source, if known, that of the first error for `Multiple`.
+    pub fn position(&self) -> Option<&LexerPosition> {
+        self.location().0.as_deref()
+    }
+
+    /// Name of the template the error is in, if it was loaded by name.
+    pub fn template(&self) -> Option<&str> {
+        self.location().1.as_ref().map(|template| template.as_str())
+    }
+
+    /// Description of the error without its location.
+    pub fn message(&self) -> String {
+        match self {
+            TemplateError::Function { message, .. }
+            | TemplateError::Load { message, .. }
+            | TemplateError::Render { message, .. }
+            | TemplateError::Type { message, .. } => message.to_string(),
+            TemplateError::Io { error, .. } => {
+                format!("Failed to write output, error: {}", error)
+            }
+            TemplateError::Lex { message, .. } => format!("Failed to lex form, error: {}", message),
+            TemplateError::Multiple { errors } => errors
+                .iter()
+                .map(|error| error.message())
+                .collect::<Vec<String>>()
+                .join("; "),
+            TemplateError::Parse { message, .. } => {
+                format!("Failed to parse tokens, error: {}", message)
+            }
+            TemplateError::UndefinedVariable { name, .. } => {
+                format!("Reference to undefined variable: {}", name)
+            }
+        }
+    }
+
+    /// The error at `position`, unless it already has a more precise one.
+    pub fn at(mut self, position: &LexerPosition) -> TemplateError {
+        {
+            let (current, _) = self.location_mut();
+            if current.is_none() {
+                *current = Some(Box::new(position.clone()));
+            }
+        }
+        self
+    }
+
+    /// The error in the template `name`, unless it is already known to be in another one.
+    pub fn in_template(mut self, name: &str) -> TemplateError {
+        if let TemplateError::Multiple { errors } = self {
+            return TemplateError::Multiple {
+                errors: errors
+                    .into_iter()
+                    .map(|error| error.in_template(name))
+                    .collect(),
+            };
+        }
+        {
+            let (_, current) = self.location_mut();
+            if current.is_none() {
+                *current = Some(name.to_string());
+            }
+        }
+        self
+    }
+
+    fn location(&self) -> (&Option<Box<LexerPosition>>, &Option<String>) {
+        match self {
+            TemplateError::Multiple { errors } => errors[0].location(),
+            TemplateError::Function {
+                position, template, ..
+            }
+            | TemplateError::Io {
+                position, template, ..
+            }
+            | TemplateError::Lex {
+                position, template, ..
+            }
+            | TemplateError::Load {
+                position, template, ..
+            }
+            | TemplateError::Parse {
+                position, template, ..
+            }
+            | TemplateError::Render {
+                position, template, ..
+            }
+            | TemplateError::Type {
+                position, template, ..
+            }
+            | TemplateError::UndefinedVariable {
+                position, template, ..
+            } => (position, template),
+        }
+    }
+
+    fn location_mut(&mut self) -> (&mut Option<Box<LexerPosition>>, &mut Option<String>) {
+        match self {
+            TemplateError::Multiple { errors } => errors[0].location_mut(),
+            TemplateError::Function {
+                position, template, ..
+            }
+            | TemplateError::Io {
+                position, template, ..
+            }
+            | TemplateError::Lex {
+                position, template, ..
+            }
+            | TemplateError::Load {
+                position, template, ..
+            }
+            | TemplateError::Parse {
+                position, template, ..
+            }
+            | TemplateError::Render {
+                position, template, ..
+            }
+            | TemplateError::Type {
+                position, template, ..
+            }
+            | TemplateError::UndefinedVariable {
+                position, template, ..
+            } => (position, template),
+        }
+    }
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if let TemplateError::Multiple { errors } = self {
+            // One line per error, each with its own location
+            for (index, error) in errors.iter().enumerate() {
+                if index > 0 {
+                    formatter.write_str("\n")?;
+                }
+                write!(formatter, "{}", error)?;
+            }
+            return Ok(());
+        }
+        formatter.write_str(&self.message())?;
+        if let Some(template) = self.template() {
+            write!(formatter, ", in: {}", template)?;
+        }
+        if let Some(position) = self.position() {
+            write!(formatter, ", at: {}", position)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for TemplateError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TemplateError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
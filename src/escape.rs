@@ -0,0 +1,113 @@
+/// Output context an echoed value is escaped for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Inside a `<style>` block or a `style` attribute
+    Css,
+    /// Element content and quoted attribute values
+    Html,
+    /// Attribute values, including unquoted ones
+    HtmlAttribute,
+    /// Inside a `<script>` block, quoted or not
+    JavaScript,
+    /// A component of a URL, such as a query parameter
+    Url,
+}
+
+impl Strategy {
+    /// Strategy for a name as written in templates, such as `escape('js')`.
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "css" => Some(Strategy::Css),
+            "html" => Some(Strategy::Html),
+            "html_attr" => Some(Strategy::HtmlAttribute),
+            "js" => Some(Strategy::JavaScript),
+            "url" => Some(Strategy::Url),
+            _ => None,
+        }
+    }
+
+    pub fn escape(self, string: &str) -> String {
+        match self {
+            Strategy::Css => css(string),
+            Strategy::Html => html(string),
+            Strategy::HtmlAttribute => html_attribute(string),
+            Strategy::JavaScript => javascript(string),
+            Strategy::Url => url(string),
+        }
+    }
+}
+
+/// Escape text for HTML element content and quoted attribute values.
+pub fn html(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for character in string.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Escape text for any attribute value, everything but letters, digits and
+/// `,.-_` becomes a character reference.
+pub fn html_attribute(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for character in string.chars() {
+        match character {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | ',' | '.' | '-' | '_' => escaped.push(character),
+            character => escaped.push_str(&format!("&#x{:X};", character as u32)),
+        }
+    }
+    escaped
+}
+
+/// Escape text for JavaScript, everything but letters, digits and `,._`
+/// becomes a `\uXXXX` escape, which is also valid inside JSON strings.
+pub fn javascript(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    let mut units = [0u16; 2];
+    for character in string.chars() {
+        match character {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | ',' | '.' | '_' => escaped.push(character),
+            character => {
+                // Characters outside the basic plane are written as surrogate pairs
+                for unit in character.encode_utf16(&mut units).iter() {
+                    escaped.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+        }
+    }
+    escaped
+}
+
+/// Escape text for CSS, everything but letters and digits becomes a hex escape
+/// terminated by a space.
+pub fn css(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for character in string.chars() {
+        match character {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => escaped.push(character),
+            character => escaped.push_str(&format!("\\{:X} ", character as u32)),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode every byte of the UTF-8 text except unreserved characters.
+pub fn url(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for byte in string.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(byte as char)
+            }
+            byte => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,642 @@
+use ast::BinaryOperator;
+use ast::Expression;
+use ast::ExpressionKind;
+use ast::Program;
+use ast::Statement;
+use ast::StatementKind;
+use error::TemplateError;
+use escape::Strategy;
+use operators;
+use scope::Scope;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use CompoundRendering;
+use DataType;
+use Environment;
+use LexerPosition;
+
+/// Nesting of included templates at which rendering fails, to stop templates
+/// including themselves.
+const MAXIMUM_INCLUDE_DEPTH: usize = 64;
+
+/// Statements of a block, with the name of the template they are in if it has one.
+type Definition = (Option<String>, Arc<Vec<Statement>>);
+
+/// Runtime variables of the templates including the one being rendered, from
+/// the innermost include out, looked up in place instead of being copied.
+struct Enclosing<'a> {
+    parent: Option<&'a Enclosing<'a>>,
+    scope: &'a Scope,
+}
+
+impl<'a> Enclosing<'a> {
+    fn get(&self, name: &str) -> Option<&DataType> {
+        match self.scope.get(name) {
+            Some(value) => Some(value),
+            None => self.parent.and_then(|parent| parent.get(name)),
+        }
+    }
+}
+
+/// Walks a syntax tree and renders it against template data and the
+/// functions, filters, globals and settings of an environment.
+pub struct Evaluator<'a> {
+    /// Output of the innermost capture, such as the overridden block rendered
+    /// by `parent()`, which is kept instead of written
+    captures: Vec<String>,
+    /// Definitions of every block by name with the template they are in, from
+    /// the extending template to its base
+    blocks: HashMap<String, Vec<Definition>>,
+    /// Name and definition level of the blocks being rendered
+    block_stack: Vec<(String, usize)>,
+    data: Option<&'a HashMap<String, DataType>>,
+    /// Number of includes this evaluator is rendered in
+    depth: usize,
+    /// Variables of the including templates, when included without a context
+    enclosing: Option<&'a Enclosing<'a>>,
+    environment: &'a Environment,
+    scope: Scope,
+    /// Name of the template whose statements are executed, for errors
+    template: Option<String>,
+    /// Destination of the output, written to as it is produced
+    writer: &'a mut dyn fmt::Write,
+}
+
+impl<'a> Evaluator<'a> {
+    /// Evaluator of the template `template`, or of a form without a name.
+    pub fn new(
+        environment: &'a Environment,
+        template: Option<&str>,
+        data: Option<&'a HashMap<String, DataType>>,
+        writer: &'a mut dyn fmt::Write,
+    ) -> Evaluator<'a> {
+        Evaluator::with_depth(environment, template, data, None, writer, 0)
+    }
+
+    /// Evaluator for a template included `depth` levels deep.
+    fn with_depth(
+        environment: &'a Environment,
+        template: Option<&str>,
+        data: Option<&'a HashMap<String, DataType>>,
+        enclosing: Option<&'a Enclosing<'a>>,
+        writer: &'a mut dyn fmt::Write,
+        depth: usize,
+    ) -> Evaluator<'a> {
+        Evaluator {
+            captures: Vec::new(),
+            blocks: HashMap::new(),
+            block_stack: Vec::new(),
+            data,
+            depth,
+            enclosing,
+            environment,
+            scope: Scope::new(),
+            template: template.map(|template| template.to_string()),
+            writer,
+        }
+    }
+
+    /// Render a program to the writer, stopping at the first error.
+    pub fn render(&mut self, program: &Program) -> Result<(), TemplateError> {
+        match self.inherit(program)? {
+            Some((template, base)) => {
+                let template = self.template.replace(template);
+                let result = self.execute(&base.statements);
+                self.template = template;
+                result
+            }
+            None => self.execute(&program.statements),
+        }
+    }
+
+    /// Collect the blocks of a template and of all templates it extends, and
+    /// return the name and program of the base template at the top of the
+    /// chain if it extends one.
+    fn inherit(
+        &mut self,
+        program: &Program,
+    ) -> Result<Option<(String, Arc<Program>)>, TemplateError> {
+        self.blocks.clear();
+        self.add_blocks(program, self.template.clone());
+        let template = self.template.clone();
+        let result = self.inherit_from(program);
+        self.template = template;
+        result
+    }
+
+    /// Follow the chain of parents up from the template being executed.
+    fn inherit_from(
+        &mut self,
+        program: &Program,
+    ) -> Result<Option<(String, Arc<Program>)>, TemplateError> {
+        let mut names: Vec<String> = Vec::new();
+        let mut base: Option<(String, Arc<Program>)> = None;
+        let mut parent = self.parent_name(&program.statements)?;
+        while let Some((name, position)) = parent {
+            if names.contains(&name) {
+                let error =
+                    TemplateError::render(format!("Circular template inheritance: {}", name));
+                return Err(self.locate(error, &position));
+            }
+            let program = self.load(&name, &position)?;
+            self.add_blocks(&program, Some(name.to_string()));
+            // The next parent is named in this one, so errors are in this one
+            self.template = Some(name.to_string());
+            parent = self.parent_name(&program.statements)?;
+            names.push(name.to_string());
+            base = Some((name, program));
+        }
+        Ok(base)
+    }
+
+    /// Add the blocks of the template `template` after those of templates extending it.
+    fn add_blocks(&mut self, program: &Program, template: Option<String>) {
+        for (name, statements) in &program.blocks {
+            self.blocks
+                .entry(name.to_string())
+                .or_default()
+                .push((template.clone(), statements.clone()));
+        }
+    }
+
+    /// Compiled template referenced by name at `position`.
+    fn load(&self, name: &str, position: &LexerPosition) -> Result<Arc<Program>, TemplateError> {
+        self.environment
+            .load(name)
+            .map_err(|error| self.locate(error, position))
+    }
+
+    /// The error at `position` in the template being executed, unless it
+    /// already has a location.
+    fn locate(&self, error: TemplateError, position: &LexerPosition) -> TemplateError {
+        let error = error.at(position);
+        match &self.template {
+            Some(template) => error.in_template(template),
+            None => error,
+        }
+    }
+
+    /// Render an included template with the current variables, or with the
+    /// entries of the map `context` as its only variables.
+    fn include(
+        &mut self,
+        name: &Expression,
+        context: &Option<Expression>,
+    ) -> Result<(), TemplateError> {
+        let template_name = match self.evaluate(name)? {
+            DataType::SafeString(value) | DataType::String(value) => value,
+            value => {
+                let error = TemplateError::type_mismatch(format!(
+                    "Type mismatch: cannot include {}",
+                    value.type_name()
+                ));
+                return Err(self.locate(error, &name.position));
+            }
+        };
+        if self.depth >= MAXIMUM_INCLUDE_DEPTH {
+            let error =
+                TemplateError::render(format!("Maximum include depth exceeded: {}", template_name));
+            return Err(self.locate(error, &name.position));
+        }
+        let context: Option<HashMap<String, DataType>> = match context {
+            Some(context) => match self.evaluate(context)? {
+                DataType::Map(map) => Some(map.into_iter().collect()),
+                value => {
+                    let error = TemplateError::type_mismatch(format!(
+                        "Type mismatch: cannot include with {} as context",
+                        value.type_name()
+                    ));
+                    return Err(self.locate(error, &context.position));
+                }
+            },
+            None => None,
+        };
+        let program = self.load(&template_name, &name.position)?;
+        // Without a context the current variables are looked up through this template
+        let enclosing = Enclosing {
+            parent: self.enclosing,
+            scope: &self.scope,
+        };
+        let (data, enclosing) = match &context {
+            Some(context) => (Some(context), None),
+            None => (self.data, Some(&enclosing)),
+        };
+        // Included output goes where this template writes to, which may be a capture
+        let writer: &mut dyn fmt::Write = match self.captures.last_mut() {
+            Some(capture) => capture,
+            None => &mut *self.writer,
+        };
+        Evaluator::with_depth(
+            self.environment,
+            Some(&template_name),
+            data,
+            enclosing,
+            writer,
+            self.depth + 1,
+        )
+        .render(&program)
+    }
+
+    /// Name and position of the template extended with `{% extends "name" %}`.
+    fn parent_name(
+        &mut self,
+        statements: &[Statement],
+    ) -> Result<Option<(String, LexerPosition)>, TemplateError> {
+        for statement in statements {
+            if let StatementKind::Extends(expression) = &statement.kind {
+                return match self.evaluate(expression)? {
+                    DataType::SafeString(name) | DataType::String(name) => {
+                        Ok(Some((name, expression.position.clone())))
+                    }
+                    value => {
+                        let error = TemplateError::type_mismatch(format!(
+                            "Type mismatch: cannot extend {}",
+                            value.type_name()
+                        ));
+                        Err(self.locate(error, &expression.position))
+                    }
+                };
+            }
+        }
+        Ok(None)
+    }
+
+    /// Render the definition at `level` of a block, where the extending template is level 0.
+    fn execute_block(&mut self, name: &str, level: usize) -> Result<(), TemplateError> {
+        let (template, statements) =
+            match self.blocks.get(name).and_then(|levels| levels.get(level)) {
+                Some((template, statements)) => (template.clone(), statements.clone()),
+                None => return Ok(()),
+            };
+        self.block_stack.push((name.to_string(), level));
+        self.scope.push();
+        let template = mem::replace(&mut self.template, template);
+        let result = self.execute(&statements);
+        self.template = template;
+        self.scope.pop();
+        self.block_stack.pop();
+        result
+    }
+
+    fn execute(&mut self, statements: &[Statement]) -> Result<(), TemplateError> {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Assign { name, value } => {
+                    let value = self.evaluate(value)?;
+                    self.assign(name, value);
+                }
+                StatementKind::Block(statements) => {
+                    self.scope.push();
+                    let result = self.execute(statements);
+                    self.scope.pop();
+                    result?;
+                }
+                StatementKind::Echo(expression) => {
+                    let value = self.evaluate(expression)?;
+                    self.write(&value)
+                        .map_err(|error| self.locate(error, &expression.position))?;
+                }
+                StatementKind::Expression(expression) => {
+                    self.evaluate(expression)?;
+                }
+                // Resolved before rendering starts
+                StatementKind::Extends(_) => {}
+                StatementKind::ForEach {
+                    subject,
+                    key,
+                    value,
+                    statements,
+                } => {
+                    let subject_value = self.evaluate(subject)?;
+                    let items = iterate(&subject_value)
+                        .map_err(|error| self.locate(error, &subject.position))?;
+                    for (item_key, item_value) in items {
+                        // Loop variables only live for one iteration of the body
+                        self.scope.push();
+                        if let Some(key) = key {
+                            self.scope.define(key, item_key);
+                        }
+                        self.scope.define(value, item_value);
+                        let result = self.execute(statements);
+                        self.scope.pop();
+                        result?;
+                    }
+                }
+                StatementKind::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let mut matched = false;
+                    for branch in branches {
+                        if self.evaluate(&branch.condition)?.is_truthy() {
+                            self.execute(&branch.statements)?;
+                            matched = true;
+                            break;
+                        }
+                    }
+                    if !matched {
+                        if let Some(statements) = otherwise {
+                            self.execute(statements)?;
+                        }
+                    }
+                }
+                StatementKind::Include { name, context } => {
+                    self.include(name, context)?;
+                }
+                StatementKind::Increment { name, step } => {
+                    let current = self.lookup(name, &statement.position)?;
+                    let value = match current {
+                        DataType::Float(value) => DataType::Float(value + *step as f64),
+                        DataType::Integer(value) => match value.checked_add(*step) {
+                            Some(value) => DataType::Integer(value),
+                            None => {
+                                let error = TemplateError::render(format!(
+                                    "Integer overflow when incrementing: {}",
+                                    name
+                                ));
+                                return Err(self.locate(error, &statement.position));
+                            }
+                        },
+                        _ => {
+                            let error = TemplateError::type_mismatch(format!(
+                                "Type mismatch: cannot increment {}: {}",
+                                current.type_name(),
+                                name
+                            ));
+                            return Err(self.locate(error, &statement.position));
+                        }
+                    };
+                    self.assign(name, value);
+                }
+                StatementKind::Inline(string) => {
+                    self.push(string)
+                        .map_err(|error| self.locate(error, &statement.position))?;
+                }
+                StatementKind::NamedBlock { name, .. } => {
+                    self.execute_block(name, 0)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn evaluate(&mut self, expression: &Expression) -> Result<DataType, TemplateError> {
+        match &expression.kind {
+            ExpressionKind::Binary {
+                left,
+                operator,
+                operator_position,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+                // Logical operators short-circuit on the left operand
+                match operator {
+                    BinaryOperator::And if !left.is_truthy() => {
+                        return Ok(operators::boolean(false));
+                    }
+                    BinaryOperator::Or if left.is_truthy() => {
+                        return Ok(operators::boolean(true));
+                    }
+                    _ => {}
+                }
+                let right = self.evaluate(right)?;
+                operators::binary(*operator, &left, &right)
+                    .map_err(|error| self.locate(error, operator_position))
+            }
+            ExpressionKind::Call { name, arguments } => {
+                let mut values: Vec<DataType> = Vec::with_capacity(arguments.len());
+                for argument in arguments {
+                    values.push(self.evaluate(argument)?);
+                }
+                // The built-in parent renders the overridden definition of the current block
+                if name == "parent" {
+                    let (block, level) = match self.block_stack.last() {
+                        Some((block, level)) if values.is_empty() => (block.to_string(), *level),
+                        Some(_) => {
+                            let error = TemplateError::function(
+                                name,
+                                format!(
+                                    "Function parent expects 0 arguments, got {}",
+                                    values.len()
+                                ),
+                            );
+                            return Err(self.locate(error, &expression.position));
+                        }
+                        None => {
+                            let error = TemplateError::function(
+                                name,
+                                "Call to parent outside of a block".to_string(),
+                            );
+                            return Err(self.locate(error, &expression.position));
+                        }
+                    };
+                    if self.blocks[&block].len() <= level + 1 {
+                        let error = TemplateError::function(
+                            name,
+                            format!("Call to parent in block without parent: {}", block),
+                        );
+                        return Err(self.locate(error, &expression.position));
+                    }
+                    self.captures.push(String::new());
+                    let result = self.execute_block(&block, level + 1);
+                    let rendered = self.captures.pop().unwrap_or_default();
+                    result?;
+                    return Ok(DataType::SafeString(rendered));
+                }
+                // The built-in echo writes its arguments to the output
+                if name == "echo" {
+                    for value in &values {
+                        self.write(value)
+                            .map_err(|error| self.locate(error, &expression.position))?;
+                    }
+                    return Ok(DataType::String(String::new()));
+                }
+                self.environment
+                    .functions
+                    .call(name, &values)
+                    .map_err(|error| self.locate(error, &expression.position))
+            }
+            ExpressionKind::Filter {
+                arguments,
+                name,
+                name_position,
+                subject,
+            } => {
+                let mut values: Vec<DataType> = Vec::with_capacity(arguments.len() + 1);
+                values.push(self.evaluate(subject)?);
+                for argument in arguments {
+                    values.push(self.evaluate(argument)?);
+                }
+                self.environment
+                    .filters
+                    .call(name, &values)
+                    .map_err(|error| self.locate(error, name_position))
+            }
+            ExpressionKind::Index { subject, key } => {
+                let subject = self.evaluate(subject)?;
+                let key_value = self.evaluate(key)?;
+                index(&subject, &key_value).map_err(|error| self.locate(error, &key.position))
+            }
+            ExpressionKind::Literal(value) => Ok(value.clone()),
+            ExpressionKind::Unary { operator, operand } => {
+                let operand = self.evaluate(operand)?;
+                operators::unary(*operator, &operand)
+                    .map_err(|error| self.locate(error, &expression.position))
+            }
+            ExpressionKind::Variable(name) => self.lookup(name, &expression.position),
+        }
+    }
+
+    fn write(&mut self, value: &DataType) -> Result<(), TemplateError> {
+        let escape = if self.environment.auto_escape {
+            Some(self.environment.escape_strategy)
+        } else {
+            None
+        };
+        let rendered = render(value, &self.environment.compound_rendering, escape)?;
+        self.push(&rendered)
+    }
+
+    /// Append text to the innermost capture, or write it out when there is none.
+    fn push(&mut self, text: &str) -> Result<(), TemplateError> {
+        match self.captures.last_mut() {
+            Some(capture) => {
+                capture.push_str(text);
+                Ok(())
+            }
+            None => self
+                .writer
+                .write_str(text)
+                .map_err(|_| TemplateError::io(io::Error::other("formatter error"))),
+        }
+    }
+
+    /// Assign a variable in the runtime scopes. A variable shadowing template
+    /// data or a global is declared in the template scope, so changes made in
+    /// a loop or block are kept after it like for the data itself.
+    fn assign(&mut self, name: &str, value: DataType) {
+        let external = self.data.is_some_and(|data| data.contains_key(name))
+            || self
+                .enclosing
+                .is_some_and(|enclosing| enclosing.get(name).is_some())
+            || self.environment.globals.contains_key(name);
+        if external && self.scope.get(name).is_none() {
+            self.scope.define_outermost(name, value);
+        } else {
+            self.scope.assign(name, value);
+        }
+    }
+
+    /// Resolve a variable from the runtime scopes first, then those of the
+    /// including templates, the template data and the globals of the environment last.
+    fn lookup(&self, name: &str, position: &LexerPosition) -> Result<DataType, TemplateError> {
+        if let Some(value) = self.scope.get(name) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.enclosing.and_then(|enclosing| enclosing.get(name)) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.data.and_then(|data_store| data_store.get(name)) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.environment.globals.get(name) {
+            return Ok(value.clone());
+        }
+        Err(self.locate(TemplateError::undefined_variable(name), position))
+    }
+}
+
+/// Member of a map by string key or item of a list by integer index, negative
+/// indices count from the end of the list.
+fn index(subject: &DataType, key: &DataType) -> Result<DataType, TemplateError> {
+    match (subject, key) {
+        (DataType::Map(map), DataType::SafeString(key))
+        | (DataType::Map(map), DataType::String(key)) => match map.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => Err(TemplateError::render(format!(
+                "Reference to undefined key: {}",
+                key
+            ))),
+        },
+        (DataType::List(items), DataType::Integer(index)) => {
+            let length = items.len() as i64;
+            let position = if *index < 0 { length + index } else { *index };
+            if position < 0 || position >= length {
+                return Err(TemplateError::render(format!(
+                    "Index out of range: {} for list of length {}",
+                    index, length
+                )));
+            }
+            Ok(items[position as usize].clone())
+        }
+        _ => Err(TemplateError::type_mismatch(format!(
+            "Type mismatch: cannot index {} with {}",
+            subject.type_name(),
+            key.type_name()
+        ))),
+    }
+}
+
+/// Text of a value as it is written to the output, strings are escaped with
+/// `escape` unless they are marked as safe.
+fn render(
+    value: &DataType,
+    compound_rendering: &CompoundRendering,
+    escape: Option<Strategy>,
+) -> Result<String, TemplateError> {
+    match value {
+        DataType::Bool(value) => Ok(format!("{}", value)),
+        DataType::Float(value) => Ok(format!("{}", value)),
+        DataType::Integer(value) => Ok(format!("{}", value)),
+        DataType::List(_) | DataType::Map(_) => match compound_rendering {
+            CompoundRendering::Error => Err(TemplateError::type_mismatch(format!(
+                "Cannot render {} as text",
+                value.type_name()
+            ))),
+            CompoundRendering::Dump => match escape {
+                Some(strategy) => Ok(strategy.escape(&value.dump())),
+                None => Ok(value.dump()),
+            },
+            CompoundRendering::Join(separator) => {
+                let items: Vec<&DataType> = match value {
+                    DataType::Map(map) => map.values().collect(),
+                    DataType::List(items) => items.iter().collect(),
+                    _ => Vec::new(),
+                };
+                let mut parts: Vec<String> = Vec::with_capacity(items.len());
+                for item in items {
+                    parts.push(render(item, compound_rendering, escape)?);
+                }
+                Ok(parts.join(separator))
+            }
+        },
+        DataType::Null => Ok(String::new()),
+        DataType::SafeString(value) => Ok(value.to_string()),
+        DataType::String(value) => match escape {
+            Some(strategy) => Ok(strategy.escape(value)),
+            None => Ok(value.to_string()),
+        },
+    }
+}
+
+/// Key and value pairs of an iterable value, lists are keyed by their index.
+fn iterate(subject: &DataType) -> Result<Vec<(DataType, DataType)>, TemplateError> {
+    match subject {
+        DataType::List(items) => Ok(items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| (DataType::Integer(index as i64), item.clone()))
+            .collect()),
+        DataType::Map(map) => Ok(map
+            .iter()
+            .map(|(key, value)| (DataType::String(key.to_string()), value.clone()))
+            .collect()),
+        _ => Err(TemplateError::type_mismatch(format!(
+            "Cannot iterate over {}",
+            subject.type_name()
+        ))),
+    }
+}
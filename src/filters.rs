@@ -0,0 +1,146 @@
+use escape::Strategy;
+use functions::ArgumentType;
+use functions::Functions;
+use DataType;
+
+// Setup built-in filters here
+pub fn get_filters() -> Functions {
+    let mut filters = Functions::with_kind("Filter");
+
+    // capitalize
+    filters.register("capitalize", vec![ArgumentType::String], |arguments| {
+        let value = string(&arguments[0]);
+        let mut characters = value.chars();
+        Ok(like(
+            &arguments[0],
+            match characters.next() {
+                Some(first) => first.to_uppercase().chain(characters).collect(),
+                None => String::new(),
+            },
+        ))
+    });
+
+    // default
+    filters.register(
+        "default",
+        vec![ArgumentType::Any, ArgumentType::Any],
+        |arguments| match &arguments[0] {
+            DataType::Null => Ok(arguments[1].clone()),
+            DataType::SafeString(value) | DataType::String(value) if value.is_empty() => {
+                Ok(arguments[1].clone())
+            }
+            value => Ok(value.clone()),
+        },
+    );
+
+    // escape
+    filters.register(
+        "escape",
+        vec![ArgumentType::Any, ArgumentType::String],
+        |arguments| match Strategy::from_name(string(&arguments[1])) {
+            Some(strategy) => Ok(DataType::SafeString(strategy.escape(&text(&arguments[0])))),
+            None => Err(format!(
+                "Unknown escaping strategy: {}",
+                string(&arguments[1])
+            )),
+        },
+    );
+
+    // join
+    filters.register(
+        "join",
+        vec![ArgumentType::List, ArgumentType::String],
+        |arguments| match &arguments[0] {
+            DataType::List(items) => {
+                let items: Vec<String> = items.iter().map(text).collect();
+                Ok(DataType::String(items.join(string(&arguments[1]))))
+            }
+            _ => Err("Expected list".to_string()),
+        },
+    );
+
+    // length
+    filters.register(
+        "length",
+        vec![ArgumentType::Any],
+        |arguments| match &arguments[0] {
+            DataType::List(items) => Ok(DataType::Integer(items.len() as i64)),
+            DataType::Map(map) => Ok(DataType::Integer(map.len() as i64)),
+            DataType::SafeString(value) | DataType::String(value) => {
+                Ok(DataType::Integer(value.chars().count() as i64))
+            }
+            value => Err(format!("Cannot take length of {}", value.type_name())),
+        },
+    );
+
+    // lower
+    filters.register("lower", vec![ArgumentType::String], |arguments| {
+        Ok(like(&arguments[0], string(&arguments[0]).to_lowercase()))
+    });
+
+    // raw
+    filters.register(
+        "raw",
+        vec![ArgumentType::Any],
+        |arguments| match &arguments[0] {
+            DataType::String(value) => Ok(DataType::SafeString(value.to_string())),
+            value => Ok(value.clone()),
+        },
+    );
+
+    // trim
+    filters.register("trim", vec![ArgumentType::String], |arguments| {
+        Ok(like(
+            &arguments[0],
+            string(&arguments[0]).trim().to_string(),
+        ))
+    });
+
+    // truncate
+    filters.register(
+        "truncate",
+        vec![ArgumentType::String, ArgumentType::Integer],
+        |arguments| {
+            let value = string(&arguments[0]);
+            let length = match arguments[1] {
+                DataType::Integer(length) if length >= 0 => length as usize,
+                _ => return Err("Expected a length of zero or more".to_string()),
+            };
+            if value.chars().count() <= length {
+                return Ok(arguments[0].clone());
+            }
+            let mut truncated: String = value.chars().take(length).collect();
+            truncated.push_str("...");
+            Ok(like(&arguments[0], truncated))
+        },
+    );
+
+    // upper
+    filters.register("upper", vec![ArgumentType::String], |arguments| {
+        Ok(like(&arguments[0], string(&arguments[0]).to_uppercase()))
+    });
+
+    filters
+}
+
+/// Contents of an argument already checked to be a string.
+fn string(value: &DataType) -> &str {
+    value.as_str().unwrap_or("")
+}
+
+/// Changed text of a string, still safe if the original was.
+fn like(value: &DataType, text: String) -> DataType {
+    match value {
+        DataType::SafeString(_) => DataType::SafeString(text),
+        _ => DataType::String(text),
+    }
+}
+
+/// Text of a joined item or an escaped value, nested lists and maps are dumped.
+fn text(value: &DataType) -> String {
+    match value {
+        DataType::Null => String::new(),
+        DataType::SafeString(value) | DataType::String(value) => value.to_string(),
+        _ => value.dump(),
+    }
+}
@@ -0,0 +1,147 @@
+use error::TemplateError;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use DataType;
+
+/// Type a native function expects for one of its arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgumentType {
+    Any,
+    Bool,
+    Float,
+    Integer,
+    List,
+    Map,
+    Number,
+    String,
+}
+
+impl ArgumentType {
+    pub fn accepts(self, value: &DataType) -> bool {
+        matches!(
+            (self, value),
+            (ArgumentType::Any, _)
+                | (ArgumentType::Bool, DataType::Bool(_))
+                | (ArgumentType::Float, DataType::Float(_))
+                | (ArgumentType::Integer, DataType::Integer(_))
+                | (ArgumentType::List, DataType::List(_))
+                | (ArgumentType::Map, DataType::Map(_))
+                | (ArgumentType::Number, DataType::Float(_))
+                | (ArgumentType::Number, DataType::Integer(_))
+                | (ArgumentType::String, DataType::SafeString(_))
+                | (ArgumentType::String, DataType::String(_))
+        )
+    }
+}
+
+/// Functions built into the evaluator, which native functions can't replace.
+pub const BUILT_IN_FUNCTIONS: [&str; 2] = ["echo", "parent"];
+
+type FunctionLogic = Arc<dyn Fn(&[DataType]) -> Result<DataType, String> + Send + Sync>;
+
+#[derive(Clone)]
+struct Function {
+    arguments: Vec<ArgumentType>,
+    logic: FunctionLogic,
+}
+
+/// Registry of native functions callable from templates as `name(arguments)`.
+///
+/// Filters use the same registry, with the filtered value as first argument.
+#[derive(Clone)]
+pub struct Functions {
+    functions: HashMap<String, Function>,
+    kind: &'static str,
+}
+
+impl Default for Functions {
+    fn default() -> Functions {
+        Functions::new()
+    }
+}
+
+impl fmt::Debug for Functions {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        formatter.debug_set().entries(names).finish()
+    }
+}
+
+impl Functions {
+    pub fn new() -> Functions {
+        Functions::with_kind("Function")
+    }
+
+    /// Empty registry whose errors call its entries `kind`, such as "Filter".
+    pub fn with_kind(kind: &'static str) -> Functions {
+        Functions {
+            functions: HashMap::new(),
+            kind,
+        }
+    }
+
+    /// Register a function, replacing any earlier function with the same name.
+    ///
+    /// Calls are checked against `arguments` before `logic` runs, so it
+    /// receives exactly as many values as there are argument types.
+    pub fn register<F>(&mut self, name: &str, arguments: Vec<ArgumentType>, logic: F)
+    where
+        F: Fn(&[DataType]) -> Result<DataType, String> + Send + Sync + 'static,
+    {
+        self.functions.insert(
+            name.to_string(),
+            Function {
+                arguments,
+                logic: Arc::new(logic),
+            },
+        );
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    pub fn call(&self, name: &str, arguments: &[DataType]) -> Result<DataType, TemplateError> {
+        let function = match self.functions.get(name) {
+            Some(function) => function,
+            None => {
+                return Err(TemplateError::function(
+                    name,
+                    format!("Call to undefined {}: {}", self.kind.to_lowercase(), name),
+                ));
+            }
+        };
+        if arguments.len() != function.arguments.len() {
+            return Err(TemplateError::function(
+                name,
+                format!(
+                    "{} {} expects {} arguments, got {}",
+                    self.kind,
+                    name,
+                    function.arguments.len(),
+                    arguments.len()
+                ),
+            ));
+        }
+        for (index, (expected, argument)) in function.arguments.iter().zip(arguments).enumerate() {
+            if !expected.accepts(argument) {
+                return Err(TemplateError::function(
+                    name,
+                    format!(
+                        "{} {} expects argument {} to be {:?}, got {}",
+                        self.kind,
+                        name,
+                        index + 1,
+                        expected,
+                        argument.type_name()
+                    ),
+                ));
+            }
+        }
+        (function.logic)(arguments).map_err(|error| {
+            TemplateError::function(name, format!("{} {} failed: {}", self.kind, name, error))
+        })
+    }
+}
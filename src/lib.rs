@@ -6,18 +6,253 @@
 
 extern crate regex;
 
+pub mod ast;
+pub mod diagnostic;
+pub mod error;
+pub mod escape;
+pub mod evaluator;
+pub mod filters;
+pub mod functions;
+pub mod loader;
+pub mod operators;
+pub mod parser;
+pub mod scope;
 pub mod tokens;
 
+use ast::Program;
+use diagnostic::Diagnostic;
+use error::TemplateError;
+use escape::Strategy;
+use evaluator::Evaluator;
+use functions::ArgumentType;
+use functions::Functions;
+use loader::TemplateLoader;
+use parser::Parser;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::num::TryFromIntError;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
 
-#[derive(Debug, PartialEq)]
+/// A value handed to or computed by a template.
+///
+/// Maps are ordered by key so rendering and iteration are deterministic.
+#[derive(Clone, Debug, PartialEq)]
 pub enum DataType {
-    Float(f32),
-    HashMap(HashMap<String, DataType>),
-    Integer(isize),
+    Bool(bool),
+    Float(f64),
+    Integer(i64),
+    List(Vec<DataType>),
+    Map(BTreeMap<String, DataType>),
+    Null,
+    /// A string trusted to be written without escaping
+    SafeString(String),
     String(String),
-    Vector(Box<DataType>),
+}
+
+impl DataType {
+    /// Name of the variant, used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DataType::Bool(_) => "bool",
+            DataType::Float(_) => "float",
+            DataType::Integer(_) => "integer",
+            DataType::List(_) => "list",
+            DataType::Map(_) => "map",
+            DataType::Null => "null",
+            DataType::SafeString(_) | DataType::String(_) => "string",
+        }
+    }
+
+    /// Truthiness used by conditions and logical operators: false, null, zero
+    /// numbers and empty strings, lists or maps are false, everything else is true.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            DataType::Bool(value) => *value,
+            DataType::Float(value) => *value != 0.0,
+            DataType::Integer(value) => *value != 0,
+            DataType::List(value) => !value.is_empty(),
+            DataType::Map(value) => !value.is_empty(),
+            DataType::Null => false,
+            DataType::SafeString(value) | DataType::String(value) => !value.is_empty(),
+        }
+    }
+
+    /// JSON-like representation, used to show compound values in output.
+    pub fn dump(&self) -> String {
+        match self {
+            DataType::Bool(value) => format!("{}", value),
+            DataType::Float(value) => format!("{}", value),
+            DataType::Integer(value) => format!("{}", value),
+            DataType::List(items) => {
+                let items: Vec<String> = items.iter().map(|item| item.dump()).collect();
+                format!("[{}]", items.join(", "))
+            }
+            DataType::Map(map) => {
+                let entries: Vec<String> = map
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", quote(key), value.dump()))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            DataType::Null => "null".to_string(),
+            DataType::SafeString(value) | DataType::String(value) => quote(value),
+        }
+    }
+
+    /// Contents of a string, whether it is marked as safe or not.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            DataType::SafeString(value) | DataType::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn quote(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for character in string.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            character if character.is_control() => {
+                quoted.push_str(&format!("\\u{:04x}", character as u32));
+            }
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// How echoing a list or map is rendered.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum CompoundRendering {
+    /// Fail rendering, so a misplaced `{{ user }}` doesn't go unnoticed
+    #[default]
+    Error,
+    /// Write a JSON-like dump of the value
+    Dump,
+    /// Write the items, or the values of a map in key order, with a separator between them
+    Join(String),
+}
+
+/// How characters inside tags that aren't part of any token are handled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum UnrecognizedCharacters {
+    /// Fail compiling, reporting every one, so a typo like `{{ a $ b }}` doesn't go unnoticed
+    #[default]
+    Error,
+    /// Skip the whole tag around each one, from its open to its close tag, and
+    /// keep a warning for it, see `Environment::take_warnings`
+    Warning,
+}
+
+impl From<bool> for DataType {
+    fn from(value: bool) -> DataType {
+        DataType::Bool(value)
+    }
+}
+
+macro_rules! from_integer {
+    ($($type:ty),*) => {
+        $(
+            impl From<$type> for DataType {
+                fn from(value: $type) -> DataType {
+                    DataType::Integer(i64::from(value))
+                }
+            }
+        )*
+    };
+}
+
+from_integer!(i8, i16, i32, i64, u8, u16, u32);
+
+/// Wider integers only convert when the value fits in an `i64`
+macro_rules! try_from_integer {
+    ($($type:ty),*) => {
+        $(
+            impl TryFrom<$type> for DataType {
+                type Error = TryFromIntError;
+
+                fn try_from(value: $type) -> Result<DataType, TryFromIntError> {
+                    i64::try_from(value).map(DataType::Integer)
+                }
+            }
+        )*
+    };
+}
+
+try_from_integer!(isize, u64, usize);
+
+impl From<f32> for DataType {
+    fn from(value: f32) -> DataType {
+        DataType::Float(f64::from(value))
+    }
+}
+
+impl From<f64> for DataType {
+    fn from(value: f64) -> DataType {
+        DataType::Float(value)
+    }
+}
+
+impl<'a> From<&'a str> for DataType {
+    fn from(value: &'a str) -> DataType {
+        DataType::String(value.to_string())
+    }
+}
+
+impl From<String> for DataType {
+    fn from(value: String) -> DataType {
+        DataType::String(value)
+    }
+}
+
+impl<T: Into<DataType>> From<Option<T>> for DataType {
+    fn from(value: Option<T>) -> DataType {
+        match value {
+            Some(value) => value.into(),
+            None => DataType::Null,
+        }
+    }
+}
+
+impl<T: Into<DataType>> From<Vec<T>> for DataType {
+    fn from(value: Vec<T>) -> DataType {
+        DataType::List(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<DataType>> From<BTreeMap<String, T>> for DataType {
+    fn from(value: BTreeMap<String, T>) -> DataType {
+        DataType::Map(
+            value
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect(),
+        )
+    }
+}
+
+impl<T: Into<DataType>> From<HashMap<String, T>> for DataType {
+    fn from(value: HashMap<String, T>) -> DataType {
+        DataType::Map(
+            value
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect(),
+        )
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -26,31 +261,43 @@ pub struct Variable {
     name: String,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum LexerToken {
     Addition,
     AddOne,
     And,
     As,
     Assign,
+    Block,
     Call(String),
     CloseCurlyBracket,
     CloseParenthesis,
+    CloseSquareBracket,
     CloseTag,
     CloseTagWithEcho,
     Comma,
+    /// Token of a matcher added with `Environment::add_lexer_matcher`: its kind
+    /// and the matched text. It is an operand calling the native function
+    /// named like its kind with the text as only argument.
+    Custom(String, String),
     Division,
+    Dot,
+    DoubleArrow,
     DoubleQuotedString(String),
+    EndBlock,
     EndForEach,
     Else,
     ElseIf,
     EndIf,
     Equals,
+    Extends,
+    False,
     Float(String),
     ForEach,
     GreaterThan,
     GreaterOrEqualThan,
     If,
+    Include,
     Inline(String),
     Integer(String),
     LesserThan,
@@ -58,362 +305,2525 @@ pub enum LexerToken {
     LesserOrGreaterThan,
     Negation,
     Multiplication,
+    Null,
     OpenCurlyBracket,
     OpenParenthesis,
+    OpenSquareBracket,
     OpenTag,
     OpenTagWithEcho,
     Or,
+    Pipe,
     Semicolon,
     SingleQuotedString(String),
     StringConcatenation,
     Subtraction,
     SubtractOne,
+    True,
+    /// Character inside a tag that isn't part of any token
+    Unrecognized(char),
     Variable(String),
+    With,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LexerState {
     Code,
     Initial,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum ParserState {
-    CodeWithEcho,
-    Code,
-    Initial,
-}
+#[derive(Clone, Debug, PartialEq)]
+pub struct LexerPosition {
+    byte_end: usize,
+    byte_start: usize,
+    column_end: usize,
+    column_start: usize,
+    line_end: usize,
+    line_start: usize,
+}
+
+impl LexerPosition {
+    /// Position of the source between two byte offsets in the form, its lines
+    /// and columns are filled in by the lexer once all tokens are found.
+    /// Offsets are always on character boundaries, so they can slice the form.
+    pub fn new(byte_start: usize, byte_end: usize) -> LexerPosition {
+        LexerPosition {
+            byte_end,
+            byte_start,
+            column_end: 0,
+            column_start: 0,
+            line_end: 0,
+            line_start: 0,
+        }
+    }
+
+    /// Byte offset in the form where the source starts.
+    pub fn byte_start(&self) -> usize {
+        self.byte_start
+    }
+
+    /// Byte offset in the form just after the source.
+    pub fn byte_end(&self) -> usize {
+        self.byte_end
+    }
+
+    /// Line the source starts on, counting from 1.
+    pub fn line_start(&self) -> usize {
+        self.line_start
+    }
+
+    /// Line just after the source, counting from 1.
+    pub fn line_end(&self) -> usize {
+        self.line_end
+    }
+
+    /// Column the source starts at, counting characters from 1.
+    pub fn column_start(&self) -> usize {
+        self.column_start
+    }
+
+    /// Column just after the source, counting characters from 1.
+    pub fn column_end(&self) -> usize {
+        self.column_end
+    }
+
+    /// Position covering everything from the start of this one to the end of `end`.
+    fn span(&self, end: &LexerPosition) -> LexerPosition {
+        LexerPosition {
+            byte_end: end.byte_end,
+            byte_start: self.byte_start,
+            column_end: end.column_end,
+            column_start: self.column_start,
+            line_end: end.line_end,
+            line_start: self.line_start,
+        }
+    }
+}
+
+impl fmt::Display for LexerPosition {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "line {}, column {}",
+            self.line_start, self.column_start
+        )
+    }
+}
+
+/// Lines and columns of offsets in a form, counted in one pass over the form
+/// as long as the offsets increase.
+struct LineCounter<'a> {
+    column: usize,
+    form: &'a str,
+    line: usize,
+    offset: usize,
+}
+
+impl<'a> LineCounter<'a> {
+    fn new(form: &'a str) -> LineCounter<'a> {
+        LineCounter {
+            column: 1,
+            form,
+            line: 1,
+            offset: 0,
+        }
+    }
+
+    /// Line and column of the character at `offset`.
+    fn count(&mut self, offset: usize) -> (usize, usize) {
+        if offset < self.offset {
+            *self = LineCounter::new(self.form);
+        }
+        for character in self.form[self.offset..offset].chars() {
+            if character == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.offset = offset;
+        (self.line, self.column)
+    }
+
+    /// Fill in the lines and columns of a position from its offsets.
+    fn locate(&mut self, position: &mut LexerPosition) {
+        let (line, column) = self.count(position.byte_start);
+        position.line_start = line;
+        position.column_start = column;
+        let (line, column) = self.count(position.byte_end);
+        position.line_end = line;
+        position.column_end = column;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LexerElement {
+    position: LexerPosition,
+    token: LexerToken,
+}
+
+impl LexerElement {
+    pub fn position(&self) -> &LexerPosition {
+        &self.position
+    }
+
+    pub fn token(&self) -> &LexerToken {
+        &self.token
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LexerTokenMatchPattern {
+    Literal(String),
+    /// Made with `LexerTokenMatchPattern::regex`
+    Regex(AnchoredRegex),
+}
+
+/// Regular expression that only matches at the start of the remaining form,
+/// so trying it never scans the rest of the form.
+#[derive(Clone, Debug)]
+pub struct AnchoredRegex(Regex);
+
+impl AnchoredRegex {
+    /// The pattern as compiled, with its anchor.
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl LexerTokenMatchPattern {
+    /// Compile a regular expression matching at the start of the remaining form,
+    /// or tell why the pattern is invalid.
+    pub fn regex(pattern: &str) -> Result<LexerTokenMatchPattern, regex::Error> {
+        let regex = Regex::new(&format!("^(?:{})", pattern))?;
+        Ok(LexerTokenMatchPattern::Regex(AnchoredRegex(regex)))
+    }
+}
+
+impl PartialEq for LexerTokenMatchPattern {
+    fn eq(&self, other: &LexerTokenMatchPattern) -> bool {
+        match (self, other) {
+            (LexerTokenMatchPattern::Literal(a), LexerTokenMatchPattern::Literal(b)) => a == b,
+            (LexerTokenMatchPattern::Regex(a), LexerTokenMatchPattern::Regex(b)) => {
+                a.as_str() == b.as_str()
+            }
+            _ => false,
+        }
+    }
+}
+
+type LexerTokenMatcherLogic = Arc<
+    dyn Fn(
+            &str,       // Buffer
+            &usize,     // Byte index of the match
+            &usize,     // Byte start of the previous match
+            &usize,     // Byte end of the previous match
+            &mut usize, // Match length in bytes
+            &mut Vec<LexerElement>,
+            &mut LexerState,
+        ) + Send
+        + Sync,
+>;
+
+#[derive(Clone)]
+pub struct LexerTokenMatcher {
+    logic: LexerTokenMatcherLogic,
+    /// State the lexer switches to after a match, if another than the current one
+    next_state: Option<LexerState>,
+    pattern: LexerTokenMatchPattern,
+    pub state: LexerState,
+}
+
+impl LexerTokenMatcher {
+    /// Matcher of `pattern` while the lexer is in `state`, turning the matched
+    /// text into a token with `token`, or into no token at all when it
+    /// returns `None`, like for a comment.
+    ///
+    /// Inline text before a match in `LexerState::Initial` becomes an inline
+    /// token of its own, like before an open tag.
+    ///
+    /// ```text
+    /// let mut matcher = LexerTokenMatcher::new(
+    ///     LexerTokenMatchPattern::Literal("<% ".to_string()),
+    ///     LexerState::Initial,
+    ///     |_| Some(LexerToken::OpenTag),
+    /// );
+    /// matcher.set_next_state(LexerState::Code);
+    /// environment.add_lexer_matcher(matcher);
+    /// ```
+    pub fn new<F>(pattern: LexerTokenMatchPattern, state: LexerState, token: F) -> LexerTokenMatcher
+    where
+        F: Fn(&str) -> Option<LexerToken> + Send + Sync + 'static,
+    {
+        let initial = state == LexerState::Initial;
+        LexerTokenMatcher {
+            logic: Arc::new(
+                move |buffer: &str,
+                      byte_index: &usize,
+                      _byte_start: &usize,
+                      byte_end: &usize,
+                      length: &mut usize,
+                      elements: &mut Vec<LexerElement>,
+                      _state: &mut LexerState| {
+                    if initial && *byte_end < *byte_index {
+                        elements.push(LexerElement {
+                            position: LexerPosition::new(*byte_end, *byte_index),
+                            token: LexerToken::Inline(buffer[*byte_end..*byte_index].to_string()),
+                        });
+                    }
+                    if let Some(token) = token(&buffer[*byte_index..(byte_index + *length)]) {
+                        elements.push(LexerElement {
+                            position: LexerPosition::new(*byte_index, byte_index + *length),
+                            token,
+                        });
+                    }
+                },
+            ),
+            next_state: None,
+            pattern,
+            state,
+        }
+    }
+
+    /// Switch the lexer to `state` after a match, such as to `LexerState::Code`
+    /// for an open tag or back to `LexerState::Initial` for a close tag.
+    pub fn set_next_state(&mut self, state: LexerState) {
+        self.next_state = Some(state);
+    }
+
+    pub fn pattern(&self) -> &LexerTokenMatchPattern {
+        &self.pattern
+    }
+
+    /// Length of the match at the start of `buffer`, or 0 without one.
+    pub fn test(&self, buffer: &str, case_sensitive: bool) -> usize {
+        match &self.pattern {
+            LexerTokenMatchPattern::Literal(pattern) => {
+                // Compare bytes so no string is allocated for every position tried
+                let end = pattern.len();
+                if buffer.len() >= end {
+                    let bytes = &buffer.as_bytes()[0..end];
+                    if (case_sensitive && bytes == pattern.as_bytes())
+                        || (!case_sensitive && bytes.eq_ignore_ascii_case(pattern.as_bytes()))
+                    {
+                        return end;
+                    }
+                }
+            }
+            LexerTokenMatchPattern::Regex(regex) => {
+                if let Some(pattern_match) = regex.0.find(buffer) {
+                    return pattern_match.end();
+                }
+            }
+        }
+        0
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute(
+        &self,
+        buffer: &str,
+        byte_index: &usize,
+        byte_start: &usize,
+        byte_end: &usize,
+        length: &mut usize,
+        elements: &mut Vec<LexerElement>,
+        state: &mut LexerState,
+    ) {
+        (*self.logic)(
+            buffer, byte_index, byte_start, byte_end, length, elements, state,
+        );
+        if let Some(next_state) = self.next_state {
+            *state = next_state;
+        }
+    }
+}
+
+impl fmt::Debug for LexerTokenMatcher {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("LexerTokenMatcher")
+            .field("next_state", &self.next_state)
+            .field("pattern", &self.pattern)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+/// Shared configuration for rendering many templates: settings, functions,
+/// filters, global variables, a loader and a cache of compiled templates.
+///
+/// It is `Send` and `Sync`, so one environment in an `Arc` serves every
+/// thread of a server.
+///
+/// ```text
+/// let mut environment = Environment::new();
+/// environment.set_loader(FileSystemLoader::new("templates"));
+/// environment.add_global("site", DataType::from("Milstian"));
+/// let page = environment.render("page.html", &context)?;
+/// ```
+#[derive(Debug)]
+pub struct Environment {
+    auto_escape: bool,
+    cache: RwLock<HashMap<String, Arc<Program>>>,
+    case_sensitive: bool,
+    compound_rendering: CompoundRendering,
+    escape_strategy: Strategy,
+    filters: Functions,
+    functions: Functions,
+    globals: HashMap<String, DataType>,
+    lexer_items: Vec<LexerTokenMatcher>,
+    loader: Option<Arc<dyn TemplateLoader>>,
+    unrecognized_characters: UnrecognizedCharacters,
+    warnings: Mutex<Vec<TemplateError>>,
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new()
+    }
+}
+
+/// A copy shares the loader, functions and matchers, and starts with the
+/// compiled templates but without the warnings of the original.
+impl Clone for Environment {
+    fn clone(&self) -> Environment {
+        Environment {
+            auto_escape: self.auto_escape,
+            cache: RwLock::new(self.cache.read().unwrap().clone()),
+            case_sensitive: self.case_sensitive,
+            compound_rendering: self.compound_rendering.clone(),
+            escape_strategy: self.escape_strategy,
+            filters: self.filters.clone(),
+            functions: self.functions.clone(),
+            globals: self.globals.clone(),
+            lexer_items: self.lexer_items.clone(),
+            loader: self.loader.clone(),
+            unrecognized_characters: self.unrecognized_characters.clone(),
+            warnings: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment {
+            auto_escape: true,
+            cache: RwLock::new(HashMap::new()),
+            case_sensitive: false,
+            compound_rendering: CompoundRendering::default(),
+            escape_strategy: Strategy::Html,
+            filters: filters::get_filters(),
+            functions: Functions::new(),
+            globals: HashMap::new(),
+            lexer_items: tokens::get_lexer_items(),
+            loader: None,
+            unrecognized_characters: UnrecognizedCharacters::default(),
+            warnings: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Turn HTML escaping of echoed strings on or off, it is on by default.
+    pub fn set_auto_escape(&mut self, auto_escape: bool) {
+        self.auto_escape = auto_escape;
+    }
+
+    /// Choose how echoed strings are escaped, such as `Strategy::JavaScript`
+    /// for a script, the default is HTML.
+    pub fn set_escape_strategy(&mut self, escape_strategy: Strategy) {
+        self.escape_strategy = escape_strategy;
+    }
+
+    /// Choose how echoing a list or map is rendered, the default is an error.
+    pub fn set_compound_rendering(&mut self, compound_rendering: CompoundRendering) {
+        self.compound_rendering = compound_rendering;
+    }
+
+    /// Match keywords like `if` and literal patterns only in the case they are
+    /// written in, by default ASCII case is ignored. This empties the cache.
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        self.case_sensitive = case_sensitive;
+        self.clear_cache();
+    }
+
+    /// Choose how characters inside tags that aren't part of any token are
+    /// handled, the default is an error. This empties the cache.
+    pub fn set_unrecognized_characters(&mut self, unrecognized_characters: UnrecognizedCharacters) {
+        self.unrecognized_characters = unrecognized_characters;
+        self.clear_cache();
+    }
+
+    /// Set the loader resolving templates by name, this empties the cache.
+    pub fn set_loader<L: TemplateLoader + 'static>(&mut self, loader: L) {
+        self.loader = Some(Arc::new(loader));
+        self.clear_cache();
+    }
+
+    /// Make a native function callable from templates as `name(...)`.
+    ///
+    /// The built-in functions `echo` and `parent` can't be replaced, registering
+    /// one of them is an error.
+    pub fn register_function<F>(
+        &mut self,
+        name: &str,
+        arguments: Vec<ArgumentType>,
+        logic: F,
+    ) -> Result<(), TemplateError>
+    where
+        F: Fn(&[DataType]) -> Result<DataType, String> + Send + Sync + 'static,
+    {
+        if functions::BUILT_IN_FUNCTIONS.contains(&name) {
+            return Err(TemplateError::function(
+                name,
+                format!("Cannot replace built-in function: {}", name),
+            ));
+        }
+        self.functions.register(name, arguments, logic);
+        Ok(())
+    }
+
+    /// Make a native filter usable as `value | name` or `value | name(...)`.
+    ///
+    /// The filtered value is passed as the first argument, so `arguments`
+    /// starts with its type.
+    pub fn register_filter<F>(&mut self, name: &str, arguments: Vec<ArgumentType>, logic: F)
+    where
+        F: Fn(&[DataType]) -> Result<DataType, String> + Send + Sync + 'static,
+    {
+        self.filters.register(name, arguments, logic);
+    }
+
+    /// Add a variable visible in every template, unless the context or the
+    /// template itself has one with the same name.
+    pub fn add_global(&mut self, name: &str, value: DataType) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// Add a matcher for tokens of custom syntax. It wins ties on match length
+    /// against the built-in matchers and matchers added before it, so it also
+    /// overrides them. This empties the cache.
+    pub fn add_lexer_matcher(&mut self, matcher: LexerTokenMatcher) {
+        self.lexer_items.insert(0, matcher);
+        self.clear_cache();
+    }
+
+    /// Remove the matchers of `pattern` in `state`, built-in or added, and
+    /// tell if there were any. This empties the cache.
+    pub fn remove_lexer_matcher(
+        &mut self,
+        pattern: &LexerTokenMatchPattern,
+        state: LexerState,
+    ) -> bool {
+        let count = self.lexer_items.len();
+        self.lexer_items
+            .retain(|item| !(item.pattern == *pattern && item.state == state));
+        self.clear_cache();
+        self.lexer_items.len() < count
+    }
+
+    /// Matchers the lexer tries, in order of precedence on ties.
+    pub fn lexer_matchers(&self) -> &[LexerTokenMatcher] {
+        &self.lexer_items
+    }
+
+    /// Tokens of a template form, such as for tools reading the tokens of
+    /// custom matchers.
+    pub fn tokenize(&self, form: &str) -> Result<Vec<LexerElement>, TemplateError> {
+        self.lex(form, None)
+    }
+
+    /// Warnings found while compiling templates since they were last taken.
+    pub fn take_warnings(&self) -> Vec<TemplateError> {
+        std::mem::take(&mut *self.warnings.lock().unwrap())
+    }
+
+    /// Forget compiled templates, so they are loaded again on next use.
+    pub fn clear_cache(&self) {
+        self.cache.write().unwrap().clear();
+    }
+
+    /// Render the template `name` with `context` as its variables.
+    pub fn render(
+        &self,
+        name: &str,
+        context: &HashMap<String, DataType>,
+    ) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        self.render_fmt(name, context, &mut output)?;
+        Ok(output)
+    }
+
+    /// Render the template `name` to `writer` as the output is produced.
+    pub fn render_to<W: io::Write>(
+        &self,
+        name: &str,
+        context: &HashMap<String, DataType>,
+        writer: &mut W,
+    ) -> Result<(), TemplateError> {
+        write_io(writer, |writer| self.render_fmt(name, context, writer))
+    }
+
+    /// Render the template `name` to a `fmt::Write`, such as a `fmt::Formatter`.
+    pub fn render_fmt<W: fmt::Write>(
+        &self,
+        name: &str,
+        context: &HashMap<String, DataType>,
+        writer: &mut W,
+    ) -> Result<(), TemplateError> {
+        Evaluator::new(self, Some(name), Some(context), writer).render(&*self.load(name)?)
+    }
+
+    /// Compiled template by name, from the cache or else through the loader.
+    ///
+    /// It keeps the environment alive, so it can be rendered from any thread.
+    pub fn get_template(self: &Arc<Self>, name: &str) -> Result<CompiledTemplate, TemplateError> {
+        Ok(CompiledTemplate {
+            environment: self.clone(),
+            name: Some(name.to_string()),
+            program: self.load(name)?,
+        })
+    }
+
+    /// Compile a template form that isn't loaded by name, to render it many times.
+    pub fn compile(self: &Arc<Self>, form: &str) -> Result<CompiledTemplate, TemplateError> {
+        Ok(CompiledTemplate {
+            environment: self.clone(),
+            name: None,
+            program: Arc::new(self.compile_form(form, None)?),
+        })
+    }
+
+    /// Report of an error with the offending source of the template it is in,
+    /// when that template can be loaded.
+    ///
+    /// Errors in a form compiled with `compile` have no template name, their
+    /// reports are made with `Diagnostic::new` and the form instead.
+    pub fn diagnose<'a>(&self, error: &'a TemplateError) -> Diagnostic<'a> {
+        let source = match (&self.loader, error.template()) {
+            (Some(loader), Some(name)) => loader.load(name).ok(),
+            _ => None,
+        };
+        Diagnostic::new(error, source)
+    }
+
+    fn load(&self, name: &str) -> Result<Arc<Program>, TemplateError> {
+        if let Some(program) = self.cache.read().unwrap().get(name) {
+            return Ok(program.clone());
+        }
+        let form = match &self.loader {
+            Some(loader) => loader
+                .load(name)
+                .map_err(|error| TemplateError::load(name, error))?,
+            None => {
+                return Err(TemplateError::load(
+                    name,
+                    format!("Cannot load template without a loader: {}", name),
+                ));
+            }
+        };
+        let program = Arc::new(
+            self.compile_form(&form, Some(name))
+                .map_err(|error| error.in_template(name))?,
+        );
+        self.cache
+            .write()
+            .unwrap()
+            .insert(name.to_string(), program.clone());
+        Ok(program)
+    }
+
+    /// Lex and parse a template form.
+    fn compile_form(&self, form: &str, name: Option<&str>) -> Result<Program, TemplateError> {
+        let elements = self.lex(form, name)?;
+        Ok(Program::new(Parser::new(elements).parse()?))
+    }
+
+    /// Lex a template form, turn whole words in the keyword table into their
+    /// keywords and take out the unrecognized characters, failing with all of
+    /// them or keeping them as warnings.
+    fn lex(&self, form: &str, name: Option<&str>) -> Result<Vec<LexerElement>, TemplateError> {
+        let mut elements = lex(form, &self.lexer_items, self.case_sensitive)?;
+        for index in 0..elements.len() {
+            // Members after a dot are names, like in `user.block`
+            if index > 0 && elements[index - 1].token == LexerToken::Dot {
+                continue;
+            }
+            let keyword = match &elements[index].token {
+                LexerToken::Variable(word) | LexerToken::Call(word) => {
+                    tokens::get_keyword(word, self.case_sensitive)
+                }
+                _ => None,
+            };
+            if let Some(keyword) = keyword {
+                elements[index].token = keyword;
+            }
+        }
+        let mut errors: Vec<TemplateError> = Vec::new();
+        elements.retain(|element| match element.token {
+            LexerToken::Unrecognized(character) => {
+                let message = match character {
+                    // Quotes are always matched by strings, unless they are unterminated
+                    '"' | '\'' => format!("Unterminated string, missing closing {:?}", character),
+                    _ => format!("Unrecognized character {:?}", character),
+                };
+                let mut error = TemplateError::lex(message).at(&element.position);
+                if let Some(name) = name {
+                    error = error.in_template(name);
+                }
+                errors.push(error);
+                false
+            }
+            _ => true,
+        });
+        match self.unrecognized_characters {
+            UnrecognizedCharacters::Error => {
+                if !errors.is_empty() {
+                    return Err(TemplateError::multiple(errors));
+                }
+            }
+            UnrecognizedCharacters::Warning => self.warnings.lock().unwrap().extend(errors),
+        }
+        Ok(elements)
+    }
+}
+
+/// A lexed and parsed template, rendered any number of times with different
+/// contexts without being compiled again.
+#[derive(Clone, Debug)]
+pub struct CompiledTemplate {
+    environment: Arc<Environment>,
+    /// Name of the template when it was loaded by name
+    name: Option<String>,
+    program: Arc<Program>,
+}
+
+impl CompiledTemplate {
+    /// Render the template with `context` as its variables.
+    pub fn render(&self, context: &HashMap<String, DataType>) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        self.render_fmt(context, &mut output)?;
+        Ok(output)
+    }
+
+    /// Render the template to `writer` as the output is produced, without
+    /// collecting it in memory first.
+    pub fn render_to<W: io::Write>(
+        &self,
+        context: &HashMap<String, DataType>,
+        writer: &mut W,
+    ) -> Result<(), TemplateError> {
+        write_io(writer, |writer| self.render_fmt(context, writer))
+    }
+
+    /// Render the template to a `fmt::Write`, such as a `String` or a `fmt::Formatter`.
+    pub fn render_fmt<W: fmt::Write>(
+        &self,
+        context: &HashMap<String, DataType>,
+        writer: &mut W,
+    ) -> Result<(), TemplateError> {
+        Evaluator::new(
+            &self.environment,
+            self.name.as_deref(),
+            Some(context),
+            writer,
+        )
+        .render(&self.program)
+    }
+}
+
+/// A single template form with its data, rendered in an environment of its own.
+#[derive(Debug)]
+pub struct Template {
+    data: Option<HashMap<String, DataType>>,
+    /// Shared with the templates it compiled, and copied when changed after that
+    environment: Arc<Environment>,
+    form: String,
+}
+
+impl Template {
+    pub fn new(form: String, data: Option<HashMap<String, DataType>>) -> Template {
+        Template {
+            form,
+            data,
+            environment: Arc::new(Environment::new()),
+        }
+    }
+
+    /// Turn HTML escaping of echoed strings on or off, it is on by default.
+    pub fn set_auto_escape(&mut self, auto_escape: bool) {
+        Arc::make_mut(&mut self.environment).set_auto_escape(auto_escape);
+    }
+
+    /// Choose how echoed strings are escaped, such as `Strategy::JavaScript`
+    /// for a script, the default is HTML.
+    pub fn set_escape_strategy(&mut self, escape_strategy: Strategy) {
+        Arc::make_mut(&mut self.environment).set_escape_strategy(escape_strategy);
+    }
+
+    /// Choose how echoing a list or map is rendered, the default is an error.
+    pub fn set_compound_rendering(&mut self, compound_rendering: CompoundRendering) {
+        Arc::make_mut(&mut self.environment).set_compound_rendering(compound_rendering);
+    }
+
+    /// Match keywords and literal patterns only in the case they are written in.
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        Arc::make_mut(&mut self.environment).set_case_sensitive(case_sensitive);
+    }
+
+    /// Choose how characters inside tags that aren't part of any token are
+    /// handled, the default is an error.
+    pub fn set_unrecognized_characters(&mut self, unrecognized_characters: UnrecognizedCharacters) {
+        Arc::make_mut(&mut self.environment).set_unrecognized_characters(unrecognized_characters);
+    }
+
+    /// Add a matcher for tokens of custom syntax, which wins ties against the
+    /// built-in matchers.
+    pub fn add_lexer_matcher(&mut self, matcher: LexerTokenMatcher) {
+        Arc::make_mut(&mut self.environment).add_lexer_matcher(matcher);
+    }
+
+    /// Remove the matchers of `pattern` in `state` and tell if there were any.
+    pub fn remove_lexer_matcher(
+        &mut self,
+        pattern: &LexerTokenMatchPattern,
+        state: LexerState,
+    ) -> bool {
+        Arc::make_mut(&mut self.environment).remove_lexer_matcher(pattern, state)
+    }
+
+    /// Warnings found while compiling the form or templates it loaded since
+    /// they were last taken.
+    pub fn take_warnings(&self) -> Vec<TemplateError> {
+        self.environment.take_warnings()
+    }
+
+    /// Set the loader resolving templates referenced by name, such as parents
+    /// in `{% extends "name" %}`.
+    pub fn set_loader<L: TemplateLoader + 'static>(&mut self, loader: L) {
+        Arc::make_mut(&mut self.environment).set_loader(loader);
+    }
+
+    /// Make a native function callable from the template as `name(...)`, the
+    /// built-in `echo` and `parent` can't be replaced.
+    pub fn register_function<F>(
+        &mut self,
+        name: &str,
+        arguments: Vec<ArgumentType>,
+        logic: F,
+    ) -> Result<(), TemplateError>
+    where
+        F: Fn(&[DataType]) -> Result<DataType, String> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.environment).register_function(name, arguments, logic)
+    }
+
+    /// Make a native filter usable as `value | name` or `value | name(...)`.
+    ///
+    /// The filtered value is passed as the first argument, so `arguments`
+    /// starts with its type.
+    pub fn register_filter<F>(&mut self, name: &str, arguments: Vec<ArgumentType>, logic: F)
+    where
+        F: Fn(&[DataType]) -> Result<DataType, String> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.environment).register_filter(name, arguments, logic);
+    }
+
+    /// Report of an error with the offending source, from the form itself or
+    /// from a template it loaded.
+    pub fn diagnose<'a>(&self, error: &'a TemplateError) -> Diagnostic<'a> {
+        match error.template() {
+            Some(_) => self.environment.diagnose(error),
+            None => Diagnostic::new(error, Some(self.form.to_string())),
+        }
+    }
+
+    /// Compile the form once, to render it with other data than the template data.
+    pub fn compile(&self) -> Result<CompiledTemplate, TemplateError> {
+        self.environment.compile(&self.form)
+    }
+
+    pub fn process(&self) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        self.process_fmt(&mut output)?;
+        Ok(output)
+    }
+
+    /// Process the template to `writer` as the output is produced.
+    pub fn process_to<W: io::Write>(&self, writer: &mut W) -> Result<(), TemplateError> {
+        write_io(writer, |writer| self.process_fmt(writer))
+    }
+
+    /// Process the template to a `fmt::Write`, such as a `fmt::Formatter`.
+    pub fn process_fmt<W: fmt::Write>(&self, writer: &mut W) -> Result<(), TemplateError> {
+        let lexer_elements = self.lex()?;
+        self.parse(lexer_elements, writer)
+    }
+
+    fn lex(&self) -> Result<Vec<LexerElement>, TemplateError> {
+        self.environment.lex(&self.form, None)
+    }
+
+    fn parse(
+        &self,
+        elements: Vec<LexerElement>,
+        writer: &mut dyn fmt::Write,
+    ) -> Result<(), TemplateError> {
+        let program = Program::new(Parser::new(elements).parse()?);
+        Evaluator::new(&self.environment, None, self.data.as_ref(), writer).render(&program)
+    }
+}
+
+/// Adapter writing output to an `io::Write`, keeping the error that stopped it.
+struct IoWriter<'a, W: io::Write + 'a> {
+    error: Option<io::Error>,
+    writer: &'a mut W,
+}
+
+impl<'a, W: io::Write> fmt::Write for IoWriter<'a, W> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.writer.write_all(text.as_bytes()).map_err(|error| {
+            self.error = Some(error);
+            fmt::Error
+        })
+    }
+}
+
+/// Run `render` against an `io::Write`, reporting the error of the writer
+/// where rendering stopped on it.
+fn write_io<W, F>(writer: &mut W, render: F) -> Result<(), TemplateError>
+where
+    W: io::Write,
+    F: FnOnce(&mut IoWriter<W>) -> Result<(), TemplateError>,
+{
+    let mut adapter = IoWriter {
+        error: None,
+        writer,
+    };
+    let result =
+        render(&mut adapter).and_then(|()| adapter.writer.flush().map_err(TemplateError::io));
+    match (result, adapter.error) {
+        (
+            Err(TemplateError::Io {
+                position, template, ..
+            }),
+            Some(error),
+        ) => Err(TemplateError::Io {
+            error,
+            position,
+            template,
+        }),
+        (result, _) => result,
+    }
+}
+
+fn lex(
+    form: &str,
+    items: &[LexerTokenMatcher],
+    case_sensitive: bool,
+) -> Result<Vec<LexerElement>, TemplateError> {
+    // Byte offset we are at, always on a character boundary
+    let mut byte_index: usize = 0;
+
+    // Start of latest match
+    let mut byte_start: usize = 0;
+
+    // End of latest match
+    let mut byte_end: usize = 0;
+
+    let mut elements: Vec<LexerElement> = Vec::new();
+    let mut state = LexerState::Initial;
+
+    // New algorithm here
+    let mut best_match_index: usize = 0;
+    let mut best_match_length: usize;
+
+    // Index of the first token of the latest tag, from its open tag on
+    let mut tag_start: usize = 0;
+
+    // Skipping the rest of a tag after an unrecognized character, until it is closed
+    let mut recovering = false;
+
+    while byte_index < form.len() {
+        best_match_length = 0;
+        for (index, item) in items.iter().enumerate() {
+            if item.state == state {
+                let match_length = item.test(&form[byte_index..], case_sensitive);
+                if match_length > best_match_length {
+                    best_match_length = match_length;
+                    best_match_index = index;
+                }
+            }
+        }
+
+        if best_match_length > 0 {
+            let best_match = items.get(best_match_index).unwrap();
+            let count = elements.len();
+            let previous_state = state;
+            best_match.execute(
+                form,
+                &byte_index,
+                &byte_start,
+                &byte_end,
+                &mut best_match_length,
+                &mut elements,
+                &mut state,
+            );
+            if previous_state == LexerState::Initial {
+                // Inline text before the match isn't part of the tag
+                tag_start = count
+                    + elements[count..]
+                        .iter()
+                        .take_while(|element| element.position.byte_start < byte_index)
+                        .count();
+            }
+            if recovering {
+                // Tokens of a broken tag, up to its close tag, would only cause follow-up errors
+                elements.truncate(count);
+                recovering = state != LexerState::Initial;
+            } else if let Some(index) = elements[count..]
+                .iter()
+                .position(|element| matches!(element.token, LexerToken::Unrecognized(_)))
+            {
+                // A matcher found the tag broken, like by an unterminated string
+                let element = elements.remove(count + index);
+                elements.truncate(tag_start);
+                elements.push(element);
+                recovering = state != LexerState::Initial;
+            }
+            byte_start = byte_index;
+            byte_index += best_match_length;
+            byte_end = byte_index;
+        } else {
+            // Skip the whole character, not just its first byte
+            let character = form[byte_index..].chars().next().unwrap();
+            let length = character.len_utf8();
+            if state == LexerState::Code && !recovering && !character.is_whitespace() {
+                // The whole tag is dropped, not only what follows the character
+                elements.truncate(tag_start);
+                elements.push(LexerElement {
+                    position: LexerPosition::new(byte_index, byte_index + length),
+                    token: LexerToken::Unrecognized(character),
+                });
+                recovering = true;
+            }
+            byte_index += length;
+        }
+    }
+
+    if elements.is_empty() {
+        if !form.is_empty() {
+            elements.push(LexerElement {
+                position: LexerPosition::new(0, form.len()),
+                token: LexerToken::Inline(form.to_string()),
+            });
+        }
+    } else if state == LexerState::Initial && byte_end < form.len() {
+        // Trailing inline text after the last close tag
+        elements.push(LexerElement {
+            position: LexerPosition::new(byte_end, form.len()),
+            token: LexerToken::Inline(form[byte_end..].to_string()),
+        });
+    }
+
+    // Tokens are found in order, so their lines are counted in one pass
+    let mut lines = LineCounter::new(form);
+    for element in &mut elements {
+        lines.locate(&mut element.position);
+    }
+    Ok(elements)
+}
+
+#[cfg(test)]
+#[allow(clippy::vec_init_then_push)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    #[test]
+    fn test_process() {}
+
+    #[test]
+    fn test_parse() {
+        let mut elements: Vec<LexerElement> = Vec::new();
+        elements.push(LexerElement {
+            position: LexerPosition {
+                byte_end: 7,
+                byte_start: 0,
+                column_end: 8,
+                column_start: 1,
+                line_end: 1,
+                line_start: 1,
+            },
+            token: LexerToken::Inline("Random ".to_string()),
+        });
+        let expected_string = "Random ".to_string();
+        let mut actual_string = String::new();
+        Template::new(String::new(), None)
+            .parse(elements, &mut actual_string)
+            .unwrap();
+        assert_eq!(actual_string, expected_string);
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("b".to_string(), DataType::Integer(25));
+        let expected_string = "Random 25".to_string();
+        let template = Template::new("Random {{ b }}".to_string(), Some(data));
+        let actual_string = template.process().unwrap();
+        assert_eq!(actual_string, expected_string);
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("b".to_string(), DataType::String("here".to_string()));
+        let template = Template::new(
+            "Random {{ b }} and {{ 'quoted' }}{% { (b); } %} after".to_string(),
+            Some(data),
+        );
+        assert_eq!(template.process().unwrap(), "Random here and quoted after");
+
+        let template = Template::new("Random {{ c }}".to_string(), None);
+        assert!(template.process().is_err());
+
+        let template = Template::new("Random {% { b; %}".to_string(), None);
+        assert!(template.process().is_err());
+    }
+
+    #[test]
+    fn test_data_types() {
+        assert_eq!(DataType::from(true), DataType::Bool(true));
+        assert_eq!(DataType::from(7u8), DataType::Integer(7));
+        assert_eq!(DataType::from(-7i32), DataType::Integer(-7));
+        assert_eq!(DataType::from(i64::MAX), DataType::Integer(i64::MAX));
+        assert_eq!(DataType::try_from(5usize), Ok(DataType::Integer(5)));
+        assert_eq!(DataType::try_from(-5isize), Ok(DataType::Integer(-5)));
+        assert!(DataType::try_from(u64::MAX).is_err());
+        assert_eq!(DataType::from(0.5f32), DataType::Float(0.5));
+        assert_eq!(DataType::from("text"), DataType::String("text".to_string()));
+        assert_eq!(DataType::from(None::<i32>), DataType::Null);
+        assert_eq!(DataType::from(Some(1)), DataType::Integer(1));
+        assert_eq!(
+            DataType::from(vec![1, 2]),
+            DataType::List(vec![DataType::Integer(1), DataType::Integer(2)])
+        );
+
+        let mut user: HashMap<String, Option<&str>> = HashMap::new();
+        user.insert("name".to_string(), Some("Ada"));
+        user.insert("email".to_string(), None);
+        let mut expected: BTreeMap<String, DataType> = BTreeMap::new();
+        expected.insert("email".to_string(), DataType::Null);
+        expected.insert("name".to_string(), DataType::String("Ada".to_string()));
+        assert_eq!(DataType::from(user), DataType::Map(expected));
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("admin".to_string(), DataType::from(true));
+        data.insert("deleted".to_string(), DataType::Null);
+        data.insert("big".to_string(), DataType::from(4_000_000_000u32));
+        let template = Template::new(
+            "{{ admin }}[{{ deleted }}]{% if !deleted && admin %}yes{% endif %} {{ big * 2 }}"
+                .to_string(),
+            Some(data),
+        );
+        assert_eq!(template.process().unwrap(), "true[]yes 8000000000");
+
+        let template = Template::new(
+            "{{ true }} {{ false }}[{{ null }}]{% if !null && true %}yes{% endif %}\
+             {% a = false; %}{% if a == false %} equal{% endif %}"
+                .to_string(),
+            None,
+        );
+        assert_eq!(template.process().unwrap(), "true false[]yes equal");
+        let error = Template::new("{% true = 1; %}".to_string(), None)
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Reserved word cannot be used as a variable name: true"));
+    }
+
+    #[test]
+    fn test_compound_rendering() {
+        let mut user: BTreeMap<String, DataType> = BTreeMap::new();
+        user.insert("name".to_string(), DataType::from("Ada \"A\""));
+        user.insert("tags".to_string(), DataType::from(vec!["a", "b"]));
+        user.insert("email".to_string(), DataType::Null);
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("user".to_string(), DataType::Map(user));
+        data.insert("items".to_string(), DataType::from(vec![1, 2, 3]));
+
+        let mut template = Template::new("{{ user }}".to_string(), Some(data.clone()));
+        let error = template.process().unwrap_err();
+        assert!(error.to_string().contains("Cannot render map as text"));
+        assert_eq!(error.position().unwrap().byte_start(), 3);
+
+        template.set_compound_rendering(CompoundRendering::Dump);
+        template.set_auto_escape(false);
+        assert_eq!(
+            template.process().unwrap(),
+            "{\"email\": null, \"name\": \"Ada \\\"A\\\"\", \"tags\": [\"a\", \"b\"]}"
+        );
+
+        let mut template = Template::new("{{ items }}|{% echo(user); %}".to_string(), Some(data));
+        template.set_compound_rendering(CompoundRendering::Join(", ".to_string()));
+        assert_eq!(
+            template.process().unwrap(),
+            "1, 2, 3|, Ada &quot;A&quot;, a, b"
+        );
+    }
+
+    #[test]
+    fn test_index() {
+        let mut user: BTreeMap<String, DataType> = BTreeMap::new();
+        user.insert("name".to_string(), DataType::from("Ada"));
+        user.insert("tags".to_string(), DataType::from(vec!["a", "b", "c"]));
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("user".to_string(), DataType::Map(user));
+        data.insert(
+            "items".to_string(),
+            DataType::List(vec![DataType::from(vec![1, 2]), DataType::from(vec![3, 4])]),
+        );
+        data.insert("key".to_string(), DataType::from("name"));
+        let template = Template::new(
+            "{{ user.name }} {{ user['name'] }} {{ user[key] }} {{ user.tags[0] }}{{ user.tags[-1] }} {{ items[1][0] + items[0][1 - 2] }} {% foreach (user.tags as tag) %}{{ tag }}{% endforeach %}".to_string(),
+            Some(data.clone()),
+        );
+        assert_eq!(template.process().unwrap(), "Ada Ada Ada ac 5 abc");
+
+        let error = Template::new("{{ user.email }}".to_string(), Some(data.clone()))
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Reference to undefined key: email"));
+        assert_eq!(error.position().unwrap().byte_start(), 8);
+
+        let error = Template::new("{{ items[2] }}".to_string(), Some(data.clone()))
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Index out of range: 2 for list of length 2"));
+        assert_eq!(error.position().unwrap().byte_start(), 9);
+
+        let error = Template::new("{{ items.name }}".to_string(), Some(data.clone()))
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Type mismatch: cannot index list with string"));
+
+        assert!(Template::new("{{ user. }}".to_string(), Some(data))
+            .process()
+            .is_err());
+    }
+
+    #[test]
+    fn test_operators() {
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("price".to_string(), DataType::Integer(25));
+        data.insert("quantity".to_string(), DataType::Integer(4));
+        data.insert("rate".to_string(), DataType::Float(0.5));
+        data.insert("name".to_string(), DataType::String("Milstian".to_string()));
+        let template = Template::new(
+            "{{ price * quantity }} {{ 1 + 2 * 3 }} {{ (1 + 2) * 3 }} {{ 10 - 4 - 3 }} {{ 7 / 2 }} {{ price * rate }} {{ -price + 5 }} {{ name + '!' }}".to_string(),
+            Some(data),
+        );
+        assert_eq!(
+            template.process().unwrap(),
+            "100 7 9 3 3.5 12.5 -20 Milstian!"
+        );
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("a".to_string(), DataType::Integer(2));
+        data.insert("b".to_string(), DataType::Integer(1));
+        data.insert("c".to_string(), DataType::String("".to_string()));
+        let template = Template::new(
+            "{{ a > b && !c }} {{ a < b || a == 2 }} {{ a <> 2 }} {{ a >= 2.0 }} {{ 'a' < 'b' }} {{ b == 0 && missing }}".to_string(),
+            Some(data),
+        );
+        assert_eq!(
+            template.process().unwrap(),
+            "true true false true true false"
+        );
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("name".to_string(), DataType::String("Milstian".to_string()));
+        let error = Template::new("{{ name * 2 }}".to_string(), Some(data))
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Type mismatch: cannot apply * to string and integer"));
+        assert_eq!(error.position().unwrap().byte_start(), 8);
+
+        assert!(Template::new("{{ 1 / 0 }}".to_string(), None)
+            .process()
+            .is_err());
+
+        // The remainder of the smallest integer by -1 overflows as well as its quotient
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("x".to_string(), DataType::Integer(i64::MIN));
+        let error = Template::new("{{ x / -1 }}".to_string(), Some(data.clone()))
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Integer overflow in -9223372036854775808 / -1"));
+        assert_eq!(
+            Template::new("{{ x / -2 }} {{ x / 2 }}".to_string(), Some(data))
+                .process()
+                .unwrap(),
+            "4611686018427387904 -4611686018427387904"
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        let form = "<p>{% if user %}Hello {{ user }}{% elseif guest %}Welcome guest{% else %}Please log in{% endif %}</p>";
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("user".to_string(), DataType::String("Ada".to_string()));
+        data.insert("guest".to_string(), DataType::Integer(1));
+        let template = Template::new(form.to_string(), Some(data));
+        assert_eq!(template.process().unwrap(), "<p>Hello Ada</p>");
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("user".to_string(), DataType::String("".to_string()));
+        data.insert("guest".to_string(), DataType::Integer(1));
+        let template = Template::new(form.to_string(), Some(data));
+        assert_eq!(template.process().unwrap(), "<p>Welcome guest</p>");
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("user".to_string(), DataType::String("".to_string()));
+        data.insert("guest".to_string(), DataType::Float(0.0));
+        let template = Template::new(form.to_string(), Some(data));
+        assert_eq!(template.process().unwrap(), "<p>Please log in</p>");
+
+        let form = "{% if a > b { echo(a); } else { 'b'; } %}{% if a { %}A{% } else if b { %}B{% } %}{% if 0 %}no{% else if b %}yes{% endif %}";
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("a".to_string(), DataType::Integer(0));
+        data.insert("b".to_string(), DataType::Integer(1));
+        let template = Template::new(form.to_string(), Some(data));
+        assert_eq!(template.process().unwrap(), "Byes");
+
+        assert!(DataType::List(vec![DataType::Integer(0)]).is_truthy());
+        assert!(!DataType::List(Vec::new()).is_truthy());
+        assert!(!DataType::Map(BTreeMap::new()).is_truthy());
+        assert!(!DataType::Null.is_truthy());
+        assert!(!DataType::Bool(false).is_truthy());
+
+        let template = Template::new("{% if 1 %}missing end".to_string(), None);
+        assert!(template.process().is_err());
+        let template = Template::new(
+            "{% if 1 %}a{% else %}b{% elseif 2 %}{% endif %}".to_string(),
+            None,
+        );
+        assert!(template.process().is_err());
+    }
+
+    #[test]
+    fn test_foreach() {
+        let mut prices: HashMap<String, DataType> = HashMap::new();
+        prices.insert("apple".to_string(), DataType::Integer(3));
+        prices.insert("banana".to_string(), DataType::Integer(2));
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("prices".to_string(), DataType::from(prices));
+        data.insert("items".to_string(), DataType::from(vec!["first", "second"]));
+        let template = Template::new(
+            "<ul>{% foreach (prices as name => price) %}<li>{{ name }}: {{ price * 2 }}</li>{% endforeach %}</ul>{% foreach (items as index => item) { %}[{{ index }}:{{ item }}]{% } %}".to_string(),
+            Some(data),
+        );
+        assert_eq!(
+            template.process().unwrap(),
+            "<ul><li>apple: 6</li><li>banana: 4</li></ul>[0:first][1:second]"
+        );
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("items".to_string(), DataType::Map(BTreeMap::new()));
+        data.insert("item".to_string(), DataType::String("outer".to_string()));
+        let template = Template::new(
+            "{% foreach (items as item) %}inner{% endforeach %}{{ item }}".to_string(),
+            Some(data),
+        );
+        assert_eq!(template.process().unwrap(), "outer");
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("items".to_string(), DataType::from(vec![1]));
+        let template = Template::new(
+            "{% foreach (items as item) %}{{ item }}{% endforeach %}{{ item }}".to_string(),
+            Some(data),
+        );
+        assert!(template.process().is_err());
+
+        let template = Template::new(
+            "{% foreach (1 as item) %}{% endforeach %}".to_string(),
+            None,
+        );
+        assert!(template.process().is_err());
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut prices: HashMap<String, DataType> = HashMap::new();
+        prices.insert("apple".to_string(), DataType::Integer(3));
+        prices.insert("banana".to_string(), DataType::Integer(2));
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("prices".to_string(), DataType::from(prices));
+        data.insert("a".to_string(), DataType::Integer(10));
+        let template = Template::new(
+            "{% total = 0; count = 0; %}{% foreach (prices as price) %}{% total = total + price; count++; %}{% endforeach %}{{ total }} {{ count }} {% a = a + 1; a--; a--; %}{{ a }}".to_string(),
+            Some(data),
+        );
+        assert_eq!(template.process().unwrap(), "5 2 9");
+        assert_eq!(
+            template.data.as_ref().unwrap().get("a"),
+            Some(&DataType::Integer(10))
+        );
+
+        // Variables shadowing the data outlive the loop they are assigned in
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("a".to_string(), DataType::Integer(10));
+        data.insert("items".to_string(), DataType::from(vec![1, 2, 3]));
+        let template = Template::new(
+            "{% foreach (items as i) %}{% a = a + i; %}{% endforeach %}{{ a }} {% foreach (items as i) { a++; } %}{{ a }}".to_string(),
+            Some(data),
+        );
+        assert_eq!(template.process().unwrap(), "16 19");
+        assert_eq!(
+            template.data.as_ref().unwrap().get("a"),
+            Some(&DataType::Integer(10))
+        );
+
+        let template = Template::new(
+            "{% a = 1; { b = 2; a = a + b; } %}{{ a }}".to_string(),
+            None,
+        );
+        assert_eq!(template.process().unwrap(), "3");
+
+        let template = Template::new("{% { b = 2; } %}{{ b }}".to_string(), None);
+        assert!(template.process().is_err());
+
+        let template = Template::new("{% b = 'text'; b++; %}".to_string(), None);
+        assert!(template.process().is_err());
+
+        let mut scope = scope::Scope::new();
+        scope.define("a", DataType::Integer(1));
+        scope.push();
+        scope.define("a", DataType::Integer(2));
+        scope.assign("b", DataType::Integer(3));
+        assert_eq!(scope.get("a"), Some(&DataType::Integer(2)));
+        scope.pop();
+        assert_eq!(scope.get("a"), Some(&DataType::Integer(1)));
+        assert_eq!(scope.get("b"), None);
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("name".to_string(), DataType::String("milstian".to_string()));
+        let mut template = Template::new(
+            "{{ upper(name) }} {% echo(add(1, 2.5), ' ', name); %} {{ upper(join(name, '!')) }}"
+                .to_string(),
+            Some(data),
+        );
+        template
+            .register_function(
+                "upper",
+                vec![ArgumentType::String],
+                |arguments| match &arguments[0] {
+                    DataType::String(value) => Ok(DataType::String(value.to_uppercase())),
+                    _ => Err("Expected string".to_string()),
+                },
+            )
+            .unwrap();
+        template
+            .register_function(
+                "add",
+                vec![ArgumentType::Number, ArgumentType::Number],
+                |arguments| {
+                    operators::binary(ast::BinaryOperator::Addition, &arguments[0], &arguments[1])
+                        .map_err(|error| error.to_string())
+                },
+            )
+            .unwrap();
+        template
+            .register_function(
+                "join",
+                vec![ArgumentType::String, ArgumentType::String],
+                |arguments| match (&arguments[0], &arguments[1]) {
+                    (DataType::String(a), DataType::String(b)) => {
+                        Ok(DataType::String(format!("{}{}", a, b)))
+                    }
+                    _ => Err("Expected strings".to_string()),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            template.process().unwrap(),
+            "MILSTIAN 3.5 milstian MILSTIAN!"
+        );
+
+        let mut template = Template::new("{{ fail(1) }}".to_string(), None);
+        template
+            .register_function("fail", vec![ArgumentType::Any], |_arguments| {
+                Err("Broken".to_string())
+            })
+            .unwrap();
+        let error = template.process().unwrap_err();
+        assert!(error.to_string().contains("Function fail failed: Broken"));
+
+        let mut template = Template::new("{{ upper(1) }}".to_string(), None);
+        template
+            .register_function("upper", vec![ArgumentType::String], |arguments| {
+                Ok(arguments[0].clone())
+            })
+            .unwrap();
+        let error = template.process().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Function upper expects argument 1 to be String, got integer"));
+
+        let mut template = Template::new("{{ upper('a', 'b') }}".to_string(), None);
+        template
+            .register_function("upper", vec![ArgumentType::String], |arguments| {
+                Ok(arguments[0].clone())
+            })
+            .unwrap();
+        let error = template.process().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Function upper expects 1 arguments, got 2"));
+
+        let mut template = Template::new("{% echo(1) %}".to_string(), None);
+        for name in &["echo", "parent"] {
+            let error = template
+                .register_function(name, vec![], |_arguments| Ok(DataType::Null))
+                .unwrap_err();
+            assert!(matches!(error, TemplateError::Function { .. }));
+        }
+        assert_eq!(template.process().unwrap(), "1");
+
+        let template = Template::new("{{ missing() }}".to_string(), None);
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Call to undefined function: missing"));
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert(
+            "title".to_string(),
+            DataType::from("  the milstian template framework "),
+        );
+        data.insert("tags".to_string(), DataType::from(vec!["a", "b"]));
+        data.insert("missing".to_string(), DataType::Null);
+        let mut template = Template::new(
+            "{{ title | trim | upper | truncate(12) }}|{{ title|trim|capitalize }}|{{ tags | join(', ') | length }}|{{ missing | default('none') }}|{{ tags[0] | shout | lower }}|{% if tags | length > 1 %}many{% endif %}".to_string(),
+            Some(data),
+        );
+        template.register_filter(
+            "shout",
+            vec![ArgumentType::String],
+            |arguments| match &arguments[0] {
+                DataType::String(value) => Ok(DataType::String(format!("{}!", value))),
+                _ => Err("Expected string".to_string()),
+            },
+        );
+        assert_eq!(
+            template.process().unwrap(),
+            "THE MILSTIAN...|The milstian template framework|4|none|a!|many"
+        );
+
+        let error = Template::new("{{ 'a' | missing }}".to_string(), None)
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Call to undefined filter: missing"));
+        assert_eq!(error.position().unwrap().byte_start(), 9);
+
+        let error = Template::new("{{ 1 | upper }}".to_string(), None)
+            .process()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Filter upper expects argument 1 to be String, got integer"));
+
+        assert!(Template::new("{{ 'a' | }}".to_string(), None)
+            .process()
+            .is_err());
+        assert!(Template::new("{{ 'a' | 'b' }}".to_string(), None)
+            .process()
+            .is_err());
+    }
+
+    #[test]
+    fn test_escaping() {
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert(
+            "comment".to_string(),
+            DataType::from("<script>alert('x & y')</script>"),
+        );
+        data.insert(
+            "markup".to_string(),
+            DataType::SafeString("<b>bold</b>".to_string()),
+        );
+        let form = "<p>{{ comment }}</p>{{ markup }}{{ '<i>' | raw }}{% echo(comment); %}";
+        let mut template = Template::new(form.to_string(), Some(data));
+        assert_eq!(
+            template.process().unwrap(),
+            "<p>&lt;script&gt;alert(&#x27;x &amp; y&#x27;)&lt;/script&gt;</p><b>bold</b><i>&lt;script&gt;alert(&#x27;x &amp; y&#x27;)&lt;/script&gt;"
+        );
+
+        template.set_auto_escape(false);
+        assert_eq!(
+            template.process().unwrap(),
+            "<p><script>alert('x & y')</script></p><b>bold</b><i><script>alert('x & y')</script>"
+        );
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("value".to_string(), DataType::from("a\"b' <c>&d/é😀"));
+        let template = Template::new(
+            "<a title={{ value | escape('html_attr') }}>|<script>var v = '{{ value | escape('js') }}';</script>|<p style=\"font-family: {{ value | escape('css') }}\">|?q={{ value | escape('url') }}|{{ value | escape('html') }}".to_string(),
+            Some(data.clone()),
+        );
+        assert_eq!(
+            template.process().unwrap(),
+            "<a title=a&#x22;b&#x27;&#x20;&#x3C;c&#x3E;&#x26;d&#x2F;&#xE9;&#x1F600;>|<script>var v = 'a\\u0022b\\u0027\\u0020\\u003Cc\\u003E\\u0026d\\u002F\\u00E9\\uD83D\\uDE00';</script>|<p style=\"font-family: a\\22 b\\27 \\20 \\3C c\\3E \\26 d\\2F \\E9 \\1F600 \">|?q=a%22b%27%20%3Cc%3E%26d%2F%C3%A9%F0%9F%98%80|a&quot;b&#x27; &lt;c&gt;&amp;d/é😀"
+        );
+
+        let mut template = Template::new("var v = '{{ value }}';".to_string(), Some(data));
+        template.set_escape_strategy(Strategy::JavaScript);
+        assert_eq!(
+            template.process().unwrap(),
+            "var v = 'a\\u0022b\\u0027\\u0020\\u003Cc\\u003E\\u0026d\\u002F\\u00E9\\uD83D\\uDE00';"
+        );
+
+        assert!(Template::new("{{ 'a' | escape('sql') }}".to_string(), None)
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown escaping strategy: sql"));
+
+        let template = Template::new(
+            "{{ '<a>' | raw | upper }}{% if ('a' | raw) == 'a' %}={% endif %}\
+             {{ ' <B> ' | raw | trim | lower }}{{ '<i>text</i>' | raw | truncate(3) }}\
+             {{ '<b>' | upper }}{{ ('<a>' | raw) + '' }}"
+                .to_string(),
+            None,
+        );
+        assert_eq!(
+            template.process().unwrap(),
+            "<A>=<b><i>...&lt;B&gt;&lt;a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert(
+            "base.html",
+            "<title>{% block title %}Site{% endblock %}</title><main>{% block content %}{% endblock content %}</main>{% block footer { %}(c){% } %}",
+        );
+        loader.insert(
+            "page.html",
+            "{% extends 'base.html' %}{% block title %}{{ page }} - {{ parent() }}{% endblock %}{% block content %}<p>{% block body %}Empty{% endblock %}</p>{% endblock %}",
+        );
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("page".to_string(), DataType::from("<News>"));
+        let mut template = Template::new(
+            "{% extends layout %}\n{% block body %}{{ parent() }} today{% endblock %}{% block title %}{{ parent() }}!{% endblock %}".to_string(),
+            Some(data.clone()),
+        );
+        data.insert("layout".to_string(), DataType::from("page.html"));
+        let error = template.process().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Reference to undefined variable: layout"));
+
+        template.data = Some(data);
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Cannot load template without a loader: page.html"));
+
+        template.set_loader(loader);
+        assert_eq!(
+            template.process().unwrap(),
+            "<title>&lt;News&gt; - Site!</title><main><p>Empty today</p></main>(c)"
+        );
+
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("loop.html", "{% extends 'loop.html' %}");
+        let mut template = Template::new("{% extends 'loop.html' %}".to_string(), None);
+        template.set_loader(loader);
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Circular template inheritance: loop.html"));
 
-#[derive(Debug, PartialEq)]
-pub struct LexerPosition {
-    char_end: usize,
-    char_start: usize,
-    line_end: usize,
-    line_start: usize,
-}
+        let mut template = Template::new("{% extends 'missing.html' %}".to_string(), None);
+        template.set_loader(loader::MemoryLoader::new());
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Template not found: missing.html"));
 
-#[derive(Debug, PartialEq)]
-pub struct LexerElement {
-    position: LexerPosition,
-    token: LexerToken,
-}
+        let template = Template::new(
+            "{% block a %}{{ parent() }}{% endblock %}".to_string(),
+            None,
+        );
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Call to parent in block without parent: a"));
 
-#[derive(Debug, PartialEq)]
-pub enum LexerTokenMatchPattern {
-    Literal(String),
-    Regex(String),
-}
+        let template = Template::new("{{ parent() }}".to_string(), None);
+        assert!(template.process().is_err());
 
-pub struct LexerTokenMatcher {
-    logic: Box<
-        Fn(
-            &str,       // Buffer
-            &usize,     // Character index
-            &usize,     // Character start
-            &usize,     // Character end
-            &mut usize, // Match length
-            &usize,     // Line index
-            &usize,     // Line start
-            &mut usize, // Line end
-            &mut Vec<LexerElement>,
-            &mut LexerState,
-        ),
-    >,
-    pattern: LexerTokenMatchPattern,
-    pub state: LexerState,
-}
+        let template = Template::new("{% block a %}x{% endblock b %}".to_string(), None);
+        assert!(template.process().is_err());
 
-impl LexerTokenMatcher {
-    pub fn test<'a>(&self, buffer: &'a str) -> usize {
-        match &self.pattern {
-            LexerTokenMatchPattern::Literal(pattern) => {
-                if buffer.len() >= pattern.len() {
-                    let end = pattern.len();
-                    let starts_with = &buffer[0..end];
-                    if starts_with.to_lowercase() == pattern.to_lowercase() {
-                        return pattern.len();
-                    }
-                }
-            }
-            LexerTokenMatchPattern::Regex(pattern) => {
-                let needle = format!("^{}", pattern);
-                let re_pattern = Regex::new(&needle).unwrap();
-                if let Some(pattern_match) = re_pattern.find(&buffer) {
-                    return pattern_match.end();
-                }
-            }
+        for (form, message, column) in &[
+            (
+                "{% block a %}x{% endblock %}{% block a %}y{% endblock %}",
+                "Block defined twice: a",
+                38,
+            ),
+            (
+                "{% block a %}{% block b { } %}{% block b { } %}{% endblock %}",
+                "Block defined twice: b",
+                40,
+            ),
+            (
+                "{% if 1 %}{% extends 'x' %}{% endif %}",
+                "Unexpected extends inside a body",
+                14,
+            ),
+            (
+                "{% { extends 'x'; } %}",
+                "Unexpected extends inside a body",
+                6,
+            ),
+            (
+                "{% extends 'a' %}{% block a %}{% endblock %}{% extends 'b' %}",
+                "Template extends more than one template",
+                48,
+            ),
+            (
+                "{% extends 'a' %}\n ignored {% block a %}{% endblock %}",
+                "Unexpected content outside blocks in a template that extends another",
+                18,
+            ),
+            (
+                "{% block a %}{% endblock %}{{ title }}{% extends 'a' %}",
+                "Unexpected content outside blocks in a template that extends another",
+                28,
+            ),
+        ] {
+            let error = Template::new(form.to_string(), None).process().unwrap_err();
+            assert!(matches!(error, TemplateError::Parse { .. }));
+            assert!(error.to_string().contains(message));
+            assert_eq!(error.position().unwrap().column_start(), *column);
         }
-        0
     }
 
-    pub fn execute<'a>(
-        &self,
-        buffer: &'a str,
-        char_index: &usize,
-        char_start: &usize,
-        char_end: &usize,
-        length: &mut usize,
-        line_index: &usize,
-        line_start: &usize,
-        line_end: &mut usize,
-        elements: &mut Vec<LexerElement>,
-        state: &mut LexerState,
-    ) {
-        (*self.logic)(
-            buffer, char_index, char_start, char_end, length, line_index, line_start, line_end,
-            elements, state,
-        )
-    }
-}
+    #[test]
+    fn test_environment() {
+        #[derive(Debug)]
+        struct CountingLoader {
+            loads: Arc<AtomicUsize>,
+            templates: loader::MemoryLoader,
+        }
+        impl loader::TemplateLoader for CountingLoader {
+            fn load(&self, name: &str) -> Result<String, String> {
+                self.loads.fetch_add(1, Ordering::SeqCst);
+                self.templates.load(name)
+            }
+        }
 
-#[derive(Debug, PartialEq)]
-pub struct Template {
-    data: Option<HashMap<String, DataType>>,
-    form: String,
-}
+        let mut templates = loader::MemoryLoader::new();
+        templates.insert(
+            "layout.html",
+            "<title>{{ site }}</title>{% block content %}{% endblock %}",
+        );
+        templates.insert(
+            "page.html",
+            "{% extends 'layout.html' %}{% block content %}{{ greet(name) | shout }}{% endblock %}",
+        );
+        let loads = Arc::new(AtomicUsize::new(0));
+        let mut environment = Environment::new();
+        environment.set_loader(CountingLoader {
+            loads: loads.clone(),
+            templates,
+        });
+        environment.add_global("site", DataType::from("Milstian"));
+        environment
+            .register_function("greet", vec![ArgumentType::String], |arguments| {
+                Ok(DataType::String(format!(
+                    "Hello {}",
+                    arguments[0].as_str().unwrap()
+                )))
+            })
+            .unwrap();
+        environment.register_filter("shout", vec![ArgumentType::String], |arguments| {
+            Ok(DataType::String(format!(
+                "{}!",
+                arguments[0].as_str().unwrap()
+            )))
+        });
 
-impl Template {
-    pub fn new(form: String, data: Option<HashMap<String, DataType>>) -> Template {
-        Template { form, data }
+        let mut context: HashMap<String, DataType> = HashMap::new();
+        context.insert("name".to_string(), DataType::from("Ada"));
+        assert_eq!(
+            environment.render("page.html", &context).unwrap(),
+            "<title>Milstian</title>Hello Ada!"
+        );
+        context.insert("name".to_string(), DataType::from("Bob"));
+        context.insert("site".to_string(), DataType::from("<Other>"));
+        assert_eq!(
+            environment.render("page.html", &context).unwrap(),
+            "<title>&lt;Other&gt;</title>Hello Bob!"
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        environment.clear_cache();
+        environment.set_auto_escape(false);
+        assert_eq!(
+            environment.render("page.html", &context).unwrap(),
+            "<title><Other></title>Hello Bob!"
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 4);
+
+        assert!(environment
+            .render("missing.html", &context)
+            .unwrap_err()
+            .to_string()
+            .contains("Template not found: missing.html"));
+        assert!(Environment::new()
+            .render("page.html", &context)
+            .unwrap_err()
+            .to_string()
+            .contains("Cannot load template without a loader: page.html"));
     }
 
-    pub fn process(&self) -> Result<String, String> {
-        match self.lex() {
-            Ok(lexer_elements) => match Template::parse(lexer_elements, &self.data) {
-                Ok(processed) => Ok(processed),
-                Err(error) => Err(format!("Failed to parse tokens, error: {}", error)),
-            },
-            Err(error) => Err(format!("Failed to lex form, error: {}", error)),
+    #[test]
+    fn test_environment_threads() {
+        fn need<T: Send + Sync>() {}
+        need::<Environment>();
+
+        let mut templates = loader::MemoryLoader::new();
+        templates.insert("page.html", "{{ greet(name) }}");
+        let mut environment = Environment::new();
+        environment.set_loader(templates);
+        environment
+            .register_function("greet", vec![ArgumentType::String], |arguments| {
+                Ok(DataType::String(format!(
+                    "Hello {}",
+                    arguments[0].as_str().unwrap()
+                )))
+            })
+            .unwrap();
+
+        let environment = Arc::new(environment);
+        let handles: Vec<thread::JoinHandle<String>> = (0..4)
+            .map(|index| {
+                let environment = environment.clone();
+                thread::spawn(move || {
+                    let mut context: HashMap<String, DataType> = HashMap::new();
+                    context.insert("name".to_string(), DataType::from(format!("{}", index)));
+                    environment.render("page.html", &context).unwrap()
+                })
+            })
+            .collect();
+        for (index, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), format!("Hello {}", index));
         }
     }
 
-    fn lex(&self) -> Result<Vec<LexerElement>, &str> {
-        let form: &str = &self.form;
-        // What char are we at
-        let mut char_index: usize = 0;
+    #[test]
+    fn test_compile() {
+        let template = Template::new(
+            "{% total = 0; %}{% foreach (items as item) %}{% total = total + item; %}{% endforeach %}{{ name }}: {{ total }}".to_string(),
+            None,
+        );
+        let compiled = template.compile().unwrap();
+        let mut context: HashMap<String, DataType> = HashMap::new();
+        context.insert("name".to_string(), DataType::from("first"));
+        context.insert("items".to_string(), DataType::from(vec![1, 2]));
+        assert_eq!(compiled.render(&context).unwrap(), "first: 3");
+        context.insert("name".to_string(), DataType::from("second"));
+        context.insert("items".to_string(), DataType::from(vec![3, 4, 5]));
+        assert_eq!(compiled.render(&context).unwrap(), "second: 12");
+        context.remove("items");
+        assert!(compiled.render(&context).is_err());
+
+        assert!(Template::new("{% if %}".to_string(), None)
+            .compile()
+            .unwrap_err()
+            .to_string()
+            .contains("Failed to parse tokens"));
+
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("hello.html", "Hello {{ name }}");
+        let mut environment = Environment::new();
+        environment.set_loader(loader);
+        let environment = Arc::new(environment);
+        let compiled = environment.get_template("hello.html").unwrap();
+        let mut context: HashMap<String, DataType> = HashMap::new();
+        context.insert("name".to_string(), DataType::from("Ada"));
+        assert_eq!(compiled.render(&context).unwrap(), "Hello Ada");
+        let compiled = environment.compile("Bye {{ name }}").unwrap();
+        assert_eq!(compiled.render(&context).unwrap(), "Bye Ada");
 
-        // Start of latest match
-        let mut char_start: usize = 0;
+        // Blocks are found once when compiling and shared with the statements
+        let compiled = environment
+            .compile("{% if 1 %}{% block a %}A{% endblock %}{% endif %}")
+            .unwrap();
+        let statements = match &compiled.program.statements[0].kind {
+            ast::StatementKind::If { branches, .. } => match &branches[0].statements[0].kind {
+                ast::StatementKind::NamedBlock { statements, .. } => statements.clone(),
+                kind => panic!("{:?}", kind),
+            },
+            kind => panic!("{:?}", kind),
+        };
+        assert!(Arc::ptr_eq(&compiled.program.blocks["a"], &statements));
+        assert_eq!(compiled.render(&context).unwrap(), "A");
 
-        // End of latest match
-        let mut char_end: usize = 0;
+        let compiled = environment.compile("Bye {{ name }}").unwrap();
 
-        let mut line_index: usize = 1;
-        let line_start: usize = 1;
-        let mut line_end: usize = 1;
-        let mut elements: Vec<LexerElement> = Vec::new();
-        let mut state = LexerState::Initial;
-
-        // New algorithm here
-        let mut best_match_index: usize = 0;
-        let mut best_match_length: usize;
-        let mut index: usize;
-
-        let items = tokens::get_lexer_items();
-        while char_index < form.len() {
-            println!(
-                "char_start: {}, char_end: {}, char_index: {}",
-                char_start, char_end, char_index
-            );
-            // TODO: Should track line numbers here
-            best_match_length = 0;
-            index = 0;
-            for item in &items {
-                if item.state == state {
-                    let match_length = item.test(&form[char_index..]);
-                    if match_length > 0 {
-                        if match_length > best_match_length {
-                            best_match_length = match_length;
-                            best_match_index = index;
-                        }
-                    }
+        // Compiled templates own what they need from the environment
+        fn need<T: Send + Sync + 'static>(_: &T) {}
+        need(&compiled);
+        drop(environment);
+        let rendered = thread::spawn(move || compiled.render(&context).unwrap());
+        assert_eq!(rendered.join().unwrap(), "Bye Ada");
+    }
+
+    #[test]
+    fn test_render_to() {
+        // Fails once it has accepted `capacity` bytes
+        struct LimitedWriter {
+            capacity: usize,
+            written: Vec<u8>,
+        }
+        impl io::Write for LimitedWriter {
+            fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+                if self.written.len() + buffer.len() > self.capacity {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "writer is full"));
                 }
-                index = index + 1;
+                self.written.extend_from_slice(buffer);
+                Ok(buffer.len())
             }
-
-            if best_match_length > 0 {
-                let best_match = items.get(best_match_index).unwrap();
-                best_match.execute(
-                    &form,
-                    &char_index,
-                    &char_start,
-                    &char_end,
-                    &mut best_match_length,
-                    &line_start,
-                    &line_start,
-                    &mut line_end,
-                    &mut elements,
-                    &mut state,
-                );
-                char_start = char_index;
-                char_index = char_index + best_match_length;
-                char_end = char_index;
-                line_index = line_end;
-            } else {
-                char_index = char_index + 1;
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
             }
         }
 
-        if elements.len() == 0 {
-            elements.push(LexerElement {
-                position: LexerPosition {
-                    char_end: form.len() - 1,
-                    char_start,
-                    line_end: line_index,
-                    line_start,
-                },
-                token: LexerToken::Inline(form.to_string()),
-            });
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert(
+            "base.html",
+            "<h1>{% block title %}Site{% endblock %}</h1>{% include 'footer.html' %}",
+        );
+        loader.insert("footer.html", "<p>{{ name }}</p>");
+        loader.insert(
+            "page.html",
+            "{% extends 'base.html' %}{% block title %}{{ name }} - {{ parent() }}{% include 'footer.html' %}{% endblock %}",
+        );
+        let mut environment = Environment::new();
+        environment.set_loader(loader);
+        let environment = Arc::new(environment);
+        let mut context: HashMap<String, DataType> = HashMap::new();
+        context.insert("name".to_string(), DataType::from("Ada"));
+        let expected = "<h1>Ada - Site<p>Ada</p></h1><p>Ada</p>";
+        assert_eq!(environment.render("page.html", &context).unwrap(), expected);
+
+        let mut bytes: Vec<u8> = Vec::new();
+        environment
+            .render_to("page.html", &context, &mut bytes)
+            .unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), expected);
+
+        let mut output = String::from("> ");
+        environment
+            .render_fmt("page.html", &context, &mut output)
+            .unwrap();
+        assert_eq!(output, format!("> {}", expected));
+
+        // Output before an error has already been written
+        let compiled = environment.compile("a{{ missing }}b").unwrap();
+        let mut bytes: Vec<u8> = Vec::new();
+        assert!(compiled
+            .render_to(&context, &mut bytes)
+            .unwrap_err()
+            .to_string()
+            .contains("Reference to undefined variable: missing"));
+        assert_eq!(bytes, b"a");
+
+        let mut writer = LimitedWriter {
+            capacity: 10,
+            written: Vec::new(),
+        };
+        let error = environment
+            .render_to("page.html", &context, &mut writer)
+            .unwrap_err();
+        match &error {
+            TemplateError::Io {
+                error, template, ..
+            } => {
+                assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+                assert_eq!(template.as_deref(), Some("page.html"));
+            }
+            _ => panic!("Expected output error, got {:?}", error),
         }
-        return Ok(elements);
+        assert!(error
+            .to_string()
+            .starts_with("Failed to write output, error: writer is full, in: page.html"));
+        assert_eq!(writer.written, b"<h1>Ada - ");
+
+        let template = Template::new("Hello {{ name }}".to_string(), Some(context));
+        let mut bytes: Vec<u8> = Vec::new();
+        template.process_to(&mut bytes).unwrap();
+        assert_eq!(bytes, b"Hello Ada");
+        let mut output = String::new();
+        template.process_fmt(&mut output).unwrap();
+        assert_eq!(output, "Hello Ada");
     }
 
-    fn parse(
-        elements: Vec<LexerElement>,
-        data: &Option<HashMap<String, DataType>>,
-    ) -> Result<String, String> {
-        let mut errors: String = "".to_string();
-        let mut result: String = "".to_string();
-        let mut state: ParserState = ParserState::Initial;
-        let mut echo_buffer: String = "".to_string();
-        for element in &elements {
-            match &state {
-                ParserState::Initial => {
-                    match &element.token {
-                        LexerToken::Inline(string) => {
-                            result.push_str(&string);
-                        },
-                        LexerToken::OpenTagWithEcho => {
-                            echo_buffer = "".to_string();
-                            state = ParserState::CodeWithEcho;
-                        },
-                        LexerToken::OpenTag => {
-                            state = ParserState::Code;
-                        }
-                        _ => {
-                            let error_message: String = format!(
-                                "Unexpected token: {:?}, in state: {:?}! ",
-                                element,
-                                state
-                            );
-                            errors.push_str(&error_message);
-                        }
-                    }
-                },
-                ParserState::Code => {
-                    match &element.token {
-                        LexerToken::CloseTag => {
-                            state = ParserState::Initial;
-                        }
-                        _ => {
-                            let error_message: String = format!(
-                                "Unexpected token: {:?}, in state: {:?}! ",
-                                element,
-                                state
-                            );
-                            errors.push_str(&error_message);
-                        }
-                    }
-                },
-                ParserState::CodeWithEcho => {
-                    match &element.token {
-                        LexerToken::CloseTagWithEcho => {
-                            result.push_str(&echo_buffer);
-                            state = ParserState::Initial;
-                        },
-                        LexerToken::Variable(a) => {
-                            match data {
-                                Some(data_store) => {
-                                    if let Some(variable) = data_store.get(a) {
-                                        match variable {
-                                            DataType::Float(value) => {
-                                                echo_buffer.push_str(&format!("{}", value));
-                                            },
-                                            DataType::HashMap(_value) => {
-                                                // TODO Do logic here
-                                            },
-                                            DataType::Integer(value) => {
-                                                echo_buffer.push_str(&format!("{}", value));
-                                            },
-                                            DataType::String(value) => {
-                                                echo_buffer.push_str(&format!("{}", value));
-                                            },
-                                            DataType::Vector(_functions) => {
-                                                // TODO Do logic here
-                                            }
-                                        }
-                                    } else {
-                                        let error_message: String = format!(
-                                            "Reference to undefined variable, token: {:?}, in state: {:?}! ",
-                                            element,
-                                            state
-                                        );
-                                        errors.push_str(&error_message);
-                                    }
-                                },
-                                None => {
-                                    let error_message: String = format!(
-                                        "Reference to variable when no variables are defined, token: {:?}, in state: {:?}! ",
-                                        element,
-                                        state
-                                    );
-                                    errors.push_str(&error_message);
-                                }
-                            }
-                            
-                        },
-                        _ => {
-                            let error_message: String = format!(
-                                "Unexpected token: {:?}, in state: {:?}! ",
-                                element,
-                                state
-                            );
-                            errors.push_str(&error_message);
-                        }
-                    }
-                }
+    #[test]
+    fn test_errors() {
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert(
+            "base.html",
+            "<h1>{% block title %}{{ 1 / 0 }}{% endblock %}</h1>{% block content %}{% endblock %}",
+        );
+        loader.insert("broken.html", "{% if %}");
+        loader.insert("partial.html", "{{ missing }}");
+        loader.insert(
+            "page.html",
+            "{% extends 'base.html' %}{% block content %}{{ kind | upper }}{% include kind %}{% endblock %}",
+        );
+        let mut environment = Environment::new();
+        environment.set_loader(loader);
+        fn render(environment: &Environment, kind: DataType) -> TemplateError {
+            let mut context: HashMap<String, DataType> = HashMap::new();
+            context.insert("kind".to_string(), kind);
+            environment.render("page.html", &context).unwrap_err()
+        }
+
+        // Errors in a block are in the template defining the block
+        match render(&environment, DataType::from("partial.html")) {
+            TemplateError::Render {
+                message, template, ..
+            } => {
+                assert_eq!(message, "Division by zero");
+                assert_eq!(template.as_deref(), Some("base.html"));
             }
+            error => panic!("Expected render error, got {:?}", error),
         }
-        if errors.len() > 0 {
-            return Err(errors);
+
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("broken.html", "{% if %}");
+        loader.insert("partial.html", "{{ missing }}");
+        loader.insert(
+            "page.html",
+            "{{ kind | upper }}{% include kind %}{{ kind | nope }}",
+        );
+        environment.set_loader(loader);
+
+        match render(&environment, DataType::from("partial.html")) {
+            TemplateError::UndefinedVariable {
+                name,
+                position,
+                template,
+            } => {
+                assert_eq!(name, "missing");
+                assert_eq!(position.unwrap().byte_start(), 3);
+                assert_eq!(template.as_deref(), Some("partial.html"));
+            }
+            error => panic!("Expected undefined variable, got {:?}", error),
+        }
+        match render(&environment, DataType::from("broken.html")) {
+            TemplateError::Parse { template, .. } => {
+                assert_eq!(template.as_deref(), Some("broken.html"));
+            }
+            error => panic!("Expected parse error, got {:?}", error),
+        }
+        match render(&environment, DataType::from("missing.html")) {
+            TemplateError::Load {
+                name,
+                position,
+                template,
+                ..
+            } => {
+                assert_eq!(name, "missing.html");
+                assert_eq!(position.unwrap().byte_start(), 29);
+                assert_eq!(template.as_deref(), Some("page.html"));
+            }
+            error => panic!("Expected load error, got {:?}", error),
+        }
+        match render(&environment, DataType::from(1)) {
+            TemplateError::Function { name, template, .. } => {
+                assert_eq!(name, "upper");
+                assert_eq!(template.as_deref(), Some("page.html"));
+            }
+            error => panic!("Expected function error, got {:?}", error),
         }
-        Ok(result)
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let error = Template::new("{{ 'a' - 1 }}".to_string(), None)
+            .process()
+            .unwrap_err();
+        assert!(matches!(error, TemplateError::Type { .. }));
+        assert_eq!(error.template(), None);
+        assert_eq!(
+            error.to_string(),
+            "Type mismatch: cannot apply - to string and integer, at: line 1, column 8"
+        );
+
+        let error = Template::new("{{ a }".to_string(), None)
+            .process()
+            .unwrap_err();
+        assert!(matches!(error, TemplateError::Parse { .. }));
+        let error: Box<dyn std::error::Error> = Box::new(error);
+        assert!(error
+            .to_string()
+            .starts_with("Failed to parse tokens, error: "));
+    }
 
     #[test]
-    fn test_process() {}
+    fn test_unrecognized_characters() {
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("a".to_string(), DataType::from("A"));
+        data.insert("b".to_string(), DataType::from("B"));
+
+        let template = Template::new("{{ a $ b }}".to_string(), Some(data.clone()));
+        let error = template.process().unwrap_err();
+        assert!(matches!(error, TemplateError::Lex { .. }));
+        assert_eq!(
+            error.to_string(),
+            "Failed to lex form, error: Unrecognized character '$', at: line 1, column 6"
+        );
+        assert!(template.take_warnings().is_empty());
+
+        // Every broken tag is reported at once
+        let template = Template::new(
+            "{{ a $ b }}\n{% a = 1 @ 2; %}".to_string(),
+            Some(data.clone()),
+        );
+        let error = template.process().unwrap_err();
+        assert_eq!(error.errors().len(), 2);
+        assert!(matches!(error.errors()[1], TemplateError::Lex { .. }));
+        assert_eq!(error.position().unwrap().byte_start(), 5);
+        assert_eq!(
+            error.to_string(),
+            "Failed to lex form, error: Unrecognized character '$', at: line 1, column 6
+Failed to lex form, error: Unrecognized character '@', at: line 2, column 10"
+        );
+        assert_eq!(
+            template.diagnose(&error).to_string(),
+            "error: Failed to lex form, error: Unrecognized character '$'
+ --> 1:6
+  |
+1 | {{ a $ b }}
+  |      ^
+
+error: Failed to lex form, error: Unrecognized character '@'
+ --> 2:10
+  |
+2 | {% a = 1 @ 2; %}
+  |          ^"
+        );
+
+        // Whitespace between tokens is fine
+        let template = Template::new("{{ a\n+\t\r\nb }}".to_string(), Some(data.clone()));
+        assert_eq!(template.process().unwrap(), "AB");
+
+        // Symbols outside ASCII aren't letters of names
+        for (form, message) in &[
+            (
+                "{{ a° }}",
+                "Unrecognized character '°', at: line 1, column 5",
+            ),
+            (
+                "{{ 2×a }}",
+                "Unrecognized character '×', at: line 1, column 5",
+            ),
+        ] {
+            let template = Template::new(form.to_string(), Some(data.clone()));
+            let error = template.process().unwrap_err().to_string();
+            assert!(error.ends_with(message), "{}", error);
+        }
+        let template = Template::new("{{ a\u{a0}+\u{2028}b }}".to_string(), Some(data.clone()));
+        assert_eq!(template.process().unwrap(), "AB");
+
+        // Unterminated strings aren't read as code
+        for (form, message) in &[
+            (
+                "{{ 'abc }}",
+                "Unterminated string, missing closing '\\'', at: line 1, column 4",
+            ),
+            (
+                "{{ a }}{{ \"b + a }}",
+                "Unterminated string, missing closing '\"', at: line 1, column 11",
+            ),
+        ] {
+            let template = Template::new(form.to_string(), Some(data.clone()));
+            let error = template.process().unwrap_err().to_string();
+            assert!(error.ends_with(message), "{}", error);
+        }
+
+        // Every broken tag is reported and skipped as a whole, up to its close tag
+        let mut template = Template::new(
+            "{{ a $ b }} {{ b ^ \"}}\" ° }} {% c = 1 @ 2; %}{{ a }}{{ b }}".to_string(),
+            Some(data.clone()),
+        );
+        template.set_unrecognized_characters(UnrecognizedCharacters::Warning);
+        assert_eq!(template.process().unwrap(), "  AB");
+        let warnings: Vec<String> = template
+            .take_warnings()
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "Failed to lex form, error: Unrecognized character '$', at: line 1, column 6",
+                "Failed to lex form, error: Unrecognized character '^', at: line 1, column 18",
+                "Failed to lex form, error: Unrecognized character '@', at: line 1, column 39",
+            ]
+        );
+        assert!(template.take_warnings().is_empty());
+
+        // Text around a skipped tag is kept
+        let mut template = Template::new(
+            "before {{ a $ b }} after\n{{ b }}".to_string(),
+            Some(data.clone()),
+        );
+        template.set_unrecognized_characters(UnrecognizedCharacters::Warning);
+        assert_eq!(template.process().unwrap(), "before  after\nB");
+
+        let mut template = Template::new("{% c = 1 @ 2; %}{{ c }}".to_string(), Some(data));
+        template.set_unrecognized_characters(UnrecognizedCharacters::Warning);
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Reference to undefined variable: c"));
+
+        let mut environment = Environment::new();
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("page.html", "{{ 1 # 2 }}");
+        environment.set_loader(loader);
+        let error = environment
+            .render("page.html", &HashMap::new())
+            .unwrap_err();
+        assert_eq!(error.template(), Some("page.html"));
+        assert_eq!(error.position().unwrap().byte_start(), 5);
+
+        // Templates compiled while skipping broken tags are compiled again
+        environment.set_unrecognized_characters(UnrecognizedCharacters::Warning);
+        assert_eq!(
+            environment.render("page.html", &HashMap::new()).unwrap(),
+            ""
+        );
+        assert_eq!(environment.take_warnings().len(), 1);
+        environment.set_unrecognized_characters(UnrecognizedCharacters::Error);
+        assert!(environment.render("page.html", &HashMap::new()).is_err());
+    }
 
     #[test]
-    fn test_parse() {
-        let mut elements: Vec<LexerElement> = Vec::new();
-        elements.push(LexerElement {
-            position: LexerPosition {
-                char_end: 7,
-                char_start: 0,
-                line_end: 1,
-                line_start: 1,
+    fn test_lexer_matchers() {
+        // Other delimiters in place of the built-in ones
+        let mut template = Template::new("{% 1 %}<% x = 2 %>{{ x }}".to_string(), None);
+        for (pattern, state) in &[("{% ", LexerState::Initial), (" %}", LexerState::Code)] {
+            assert!(template.remove_lexer_matcher(
+                &LexerTokenMatchPattern::Literal(pattern.to_string()),
+                *state
+            ));
+        }
+        assert!(!template.remove_lexer_matcher(
+            &LexerTokenMatchPattern::Literal("{% ".to_string()),
+            LexerState::Initial
+        ));
+        let mut open = LexerTokenMatcher::new(
+            LexerTokenMatchPattern::Literal("<% ".to_string()),
+            LexerState::Initial,
+            |_| Some(LexerToken::OpenTag),
+        );
+        open.set_next_state(LexerState::Code);
+        template.add_lexer_matcher(open);
+        let mut close = LexerTokenMatcher::new(
+            LexerTokenMatchPattern::Literal(" %>".to_string()),
+            LexerState::Code,
+            |_| Some(LexerToken::CloseTag),
+        );
+        close.set_next_state(LexerState::Initial);
+        template.add_lexer_matcher(close);
+        assert_eq!(template.process().unwrap(), "{% 1 %}2");
+
+        // Comments producing no token, and a keyword overriding a variable of the same length
+        let mut environment = Environment::new();
+        environment.add_lexer_matcher(LexerTokenMatcher::new(
+            LexerTokenMatchPattern::regex(r"\{#.*?#\}").unwrap(),
+            LexerState::Initial,
+            |_| None,
+        ));
+        environment.add_lexer_matcher(LexerTokenMatcher::new(
+            LexerTokenMatchPattern::Literal("plus".to_string()),
+            LexerState::Code,
+            |_| Some(LexerToken::Addition),
+        ));
+        let mut environment = Arc::new(environment);
+        let compiled = environment
+            .compile("a{# note #}b{{ 1 plus 2 }}{# {{ hidden }} #}")
+            .unwrap();
+        assert_eq!(compiled.render(&HashMap::new()).unwrap(), "ab3");
+        drop(compiled);
+
+        // Tokens of their own kind, which tools can read and templates use as
+        // calls of the function of that kind
+        Arc::make_mut(&mut environment).add_lexer_matcher(LexerTokenMatcher::new(
+            LexerTokenMatchPattern::regex("![a-z]+").unwrap(),
+            LexerState::Code,
+            |text| {
+                Some(LexerToken::Custom(
+                    "bang".to_string(),
+                    text[1..].to_string(),
+                ))
             },
-            token: LexerToken::Inline("Random ".to_string()),
-        });
-        let expected_string = "Random ".to_string();
-        let actual_string = Template::parse(elements, &None).unwrap();
-        assert_eq!(actual_string, expected_string);
+        ));
+        let elements = environment.tokenize("{{ a !important }}").unwrap();
+        assert_eq!(
+            elements[2].token(),
+            &LexerToken::Custom("bang".to_string(), "important".to_string())
+        );
+        assert_eq!(elements[2].position().byte_start(), 5);
+        assert_eq!(elements[2].position().column_end(), 16);
+        assert!(matches!(
+            environment.compile("{{ a !important }}"),
+            Err(TemplateError::Parse { .. })
+        ));
+        let compiled = environment.compile("{{ !important | upper }}").unwrap();
+        let error = compiled.render(&HashMap::new()).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Call to undefined function: bang"));
+        assert_eq!(error.position().unwrap().byte_start(), 3);
+        Arc::make_mut(&mut environment)
+            .register_function("bang", vec![ArgumentType::String], |arguments| {
+                Ok(DataType::String(format!(
+                    "{}!",
+                    arguments[0].as_str().unwrap()
+                )))
+            })
+            .unwrap();
+        assert_eq!(
+            environment
+                .compile("{{ !important | upper }} {% if !a == 'a!' %}yes{% endif %}")
+                .unwrap()
+                .render(&HashMap::new())
+                .unwrap(),
+            "IMPORTANT! yes"
+        );
+
+        // Regular expressions only match at the current position
+        let mut environment = Environment::new();
+        environment.add_lexer_matcher(LexerTokenMatcher::new(
+            LexerTokenMatchPattern::regex("b").unwrap(),
+            LexerState::Code,
+            |_| Some(LexerToken::Integer("0".to_string())),
+        ));
+        let compiled = Arc::new(environment).compile("{{ 1 + 2 }}b").unwrap();
+        assert_eq!(compiled.render(&HashMap::new()).unwrap(), "3b");
+        assert!(LexerTokenMatchPattern::regex("(b").is_err());
+        match LexerTokenMatchPattern::regex("b|c").unwrap() {
+            LexerTokenMatchPattern::Regex(regex) => assert_eq!(regex.as_str(), "^(?:b|c)"),
+            pattern => panic!("{:?}", pattern),
+        }
+    }
 
+    #[test]
+    fn test_keywords() {
         let mut data: HashMap<String, DataType> = HashMap::new();
-        data.insert(
-            "b".to_string(),
-            DataType::Integer(25)
+        for name in &["assets", "iffy", "elsewhere", "as_if", "With"] {
+            data.insert(name.to_string(), DataType::from(*name));
+        }
+        let mut user: HashMap<String, DataType> = HashMap::new();
+        user.insert("block".to_string(), DataType::from("b"));
+        data.insert("user".to_string(), DataType::from(user));
+        data.insert("items".to_string(), DataType::from(vec![1, 2]));
+
+        // Keywords are whole words, also right before a parenthesis, but not member names
+        let template = Template::new(
+            "{{ assets }} {{ iffy }} {{ elsewhere }} {{ as_if }} {{ user.block }} {% if(1) %}{% foreach(items as item) %}{{ item }}{% endforeach %}{% EndIf %}".to_string(),
+            Some(data.clone()),
         );
-        let expected_string = "Random 25".to_string();
-        let template = Template::new("Random {{ b }}".to_string(), Some(data));
-        let actual_string = template.process().unwrap();
-        assert_eq!(actual_string, expected_string);
+        assert_eq!(
+            template.process().unwrap(),
+            "assets iffy elsewhere as_if b 12"
+        );
+
+        // Only keywords written in lower case with case sensitivity
+        let mut template = Template::new(
+            "{% if 1 %}{{ With }}{% endif %}".to_string(),
+            Some(data.clone()),
+        );
+        template.set_case_sensitive(true);
+        assert_eq!(template.process().unwrap(), "With");
+        let mut template = Template::new("{% IF 1 %}x{% endif %}".to_string(), Some(data));
+        template.set_case_sensitive(true);
+        assert!(template.process().is_err());
+
+        for (form, column) in &[
+            ("{% if = 1 %}", 4),
+            ("{% With++ %}", 4),
+            ("{{ 1 + block }}", 8),
+            ("{% foreach (a as endif) %}{% endforeach %}", 18),
+        ] {
+            let error = Template::new(form.to_string(), None).process().unwrap_err();
+            assert!(matches!(error, TemplateError::Parse { .. }));
+            assert!(error
+                .to_string()
+                .contains("Reserved word cannot be used as a variable name"));
+            assert_eq!(error.position().unwrap().column_start(), *column);
+        }
+        let error = Template::new("{{ with }}".to_string(), None)
+            .process()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to parse tokens, error: Reserved word cannot be used as a variable name: with, at: line 1, column 4"
+        );
+
+        // Keywords of a statement that isn't open aren't names
+        for (form, message) in &[
+            (
+                "a{% endif %}",
+                "Unexpected endif without matching if, at: line 1, column 5",
+            ),
+            (
+                "{% foreach (a as b) %}{% endif %}{% endforeach %}",
+                "Unexpected endif without matching if, at: line 1, column 26",
+            ),
+            (
+                "{% if 1 %}{% else %}{% else %}{% endif %}",
+                "Unexpected else without matching if, at: line 1, column 24",
+            ),
+            (
+                "{% endforeach %}",
+                "Unexpected endforeach without matching foreach, at: line 1, column 4",
+            ),
+            (
+                "{% endblock %}",
+                "Unexpected endblock without matching block, at: line 1, column 4",
+            ),
+        ] {
+            let error = Template::new(form.to_string(), None).process().unwrap_err();
+            assert!(matches!(error, TemplateError::Parse { .. }));
+            assert_eq!(
+                error.to_string(),
+                format!("Failed to parse tokens, error: {}", message)
+            );
+        }
+    }
 
+    #[test]
+    fn test_diagnostic() {
+        let template = Template::new("<p>\n\t{{ missing }}\n</p>".to_string(), None);
+        let error = template.process().unwrap_err();
+        assert_eq!(
+            template.diagnose(&error).to_string(),
+            "error: Reference to undefined variable: missing
+ --> 2:5
+  |
+2 | \t{{ missing }}
+  | \t   ^^^^^^^"
+        );
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("items".to_string(), DataType::from(vec![1, 2]));
+        let template = Template::new("{{ (items\n) }}".to_string(), Some(data));
+        let error = template.process().unwrap_err();
+        assert_eq!(
+            template.diagnose(&error).to_string(),
+            "error: Cannot render list as text
+ --> 1:4
+  |
+1 | {{ (items
+  |    ^^^^^^
+2 | ) }}
+  | ^"
+        );
+        assert_eq!(
+            diagnostic::Diagnostic::new(&error, None).to_string(),
+            "error: Cannot render list as text\n--> 1"
+        );
+
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("page.html", "{% include 'partial.html' %}");
+        loader.insert("partial.html", "{{ missing }}");
+        let mut environment = Environment::new();
+        environment.set_loader(loader);
+        let error = environment
+            .render("page.html", &HashMap::new())
+            .unwrap_err();
+        let mut diagnostic = environment.diagnose(&error);
+        diagnostic.set_colors(true);
+        assert_eq!(
+            diagnostic.to_string(),
+            "\x1b[1;31merror:\x1b[0m Reference to undefined variable: missing
+ \x1b[1;34m-->\x1b[0m partial.html:1:4
+  \x1b[1;34m|\x1b[0m
+\x1b[1;34m1\x1b[0m \x1b[1;34m|\x1b[0m {{ missing }}
+  \x1b[1;34m|\x1b[0m    \x1b[1;31m^^^^^^^\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_parser() {
+        let elements = Template::new("A {{ b }}{% echo(c, 1.5); %}".to_string(), None)
+            .lex()
+            .unwrap();
+        let statements = Parser::new(elements).parse().unwrap();
+        assert_eq!(statements.len(), 3);
+        assert_eq!(
+            statements[0].kind,
+            ast::StatementKind::Inline("A ".to_string())
+        );
+        match &statements[1].kind {
+            ast::StatementKind::Echo(expression) => {
+                assert_eq!(
+                    expression.kind,
+                    ast::ExpressionKind::Variable("b".to_string())
+                );
+                assert_eq!(expression.position.byte_start, 5);
+            }
+            _ => panic!("Expected echo statement"),
+        }
+        match &statements[2].kind {
+            ast::StatementKind::Expression(expression) => match &expression.kind {
+                ast::ExpressionKind::Call { name, arguments } => {
+                    assert_eq!(name, "echo");
+                    assert_eq!(arguments.len(), 2);
+                    assert_eq!(
+                        arguments[1].kind,
+                        ast::ExpressionKind::Literal(DataType::Float(1.5))
+                    );
+                }
+                _ => panic!("Expected call expression"),
+            },
+            _ => panic!("Expected expression statement"),
+        }
+
+        let elements = Template::new("{% echo(a b) %}".to_string(), None)
+            .lex()
+            .unwrap();
+        assert!(Parser::new(elements).parse().is_err());
     }
 
     #[test]
@@ -422,8 +2832,10 @@ mod tests {
         let mut expected_lexed_tokens: Vec<LexerElement> = Vec::new();
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 5,
-                char_start: 0,
+                byte_end: 6,
+                byte_start: 0,
+                column_end: 7,
+                column_start: 1,
                 line_end: 1,
                 line_start: 1,
             },
@@ -440,8 +2852,10 @@ mod tests {
         let mut expected_lexed_tokens: Vec<LexerElement> = Vec::new();
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 7,
-                char_start: 0,
+                byte_end: 7,
+                byte_start: 0,
+                column_end: 8,
+                column_start: 1,
                 line_end: 1,
                 line_start: 1,
             },
@@ -449,8 +2863,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 10,
-                char_start: 7,
+                byte_end: 10,
+                byte_start: 7,
+                column_end: 11,
+                column_start: 8,
                 line_end: 1,
                 line_start: 1,
             },
@@ -458,8 +2874,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 14,
-                char_start: 10,
+                byte_end: 14,
+                byte_start: 10,
+                column_end: 15,
+                column_start: 11,
                 line_end: 1,
                 line_start: 1,
             },
@@ -467,8 +2885,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 15,
-                char_start: 14,
+                byte_end: 15,
+                byte_start: 14,
+                column_end: 16,
+                column_start: 15,
                 line_end: 1,
                 line_start: 1,
             },
@@ -476,8 +2896,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 18,
-                char_start: 15,
+                byte_end: 18,
+                byte_start: 15,
+                column_end: 19,
+                column_start: 16,
                 line_end: 1,
                 line_start: 1,
             },
@@ -485,8 +2907,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 19,
-                char_start: 18,
+                byte_end: 19,
+                byte_start: 18,
+                column_end: 20,
+                column_start: 19,
                 line_end: 1,
                 line_start: 1,
             },
@@ -494,8 +2918,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 22,
-                char_start: 19,
+                byte_end: 22,
+                byte_start: 19,
+                column_end: 23,
+                column_start: 20,
                 line_end: 1,
                 line_start: 1,
             },
@@ -503,8 +2929,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 38,
-                char_start: 22,
+                byte_end: 38,
+                byte_start: 22,
+                column_end: 39,
+                column_start: 23,
                 line_end: 1,
                 line_start: 1,
             },
@@ -512,8 +2940,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 41,
-                char_start: 38,
+                byte_end: 41,
+                byte_start: 38,
+                column_end: 42,
+                column_start: 39,
                 line_end: 1,
                 line_start: 1,
             },
@@ -521,8 +2951,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 55,
-                char_start: 41,
+                byte_end: 56,
+                byte_start: 41,
+                column_end: 57,
+                column_start: 42,
                 line_end: 1,
                 line_start: 1,
             },
@@ -530,8 +2962,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 59,
-                char_start: 56,
+                byte_end: 59,
+                byte_start: 56,
+                column_end: 60,
+                column_start: 57,
                 line_end: 1,
                 line_start: 1,
             },
@@ -548,8 +2982,10 @@ mod tests {
         let mut expected_lexed_tokens: Vec<LexerElement> = Vec::new();
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 3,
-                char_start: 0,
+                byte_end: 3,
+                byte_start: 0,
+                column_end: 4,
+                column_start: 1,
                 line_end: 1,
                 line_start: 1,
             },
@@ -557,8 +2993,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 5,
-                char_start: 3,
+                byte_end: 5,
+                byte_start: 3,
+                column_end: 6,
+                column_start: 4,
                 line_end: 1,
                 line_start: 1,
             },
@@ -566,8 +3004,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 7,
-                char_start: 6,
+                byte_end: 7,
+                byte_start: 6,
+                column_end: 8,
+                column_start: 7,
                 line_end: 1,
                 line_start: 1,
             },
@@ -575,8 +3015,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 9,
-                char_start: 8,
+                byte_end: 9,
+                byte_start: 8,
+                column_end: 10,
+                column_start: 9,
                 line_end: 1,
                 line_start: 1,
             },
@@ -584,8 +3026,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 11,
-                char_start: 10,
+                byte_end: 11,
+                byte_start: 10,
+                column_end: 12,
+                column_start: 11,
                 line_end: 1,
                 line_start: 1,
             },
@@ -593,8 +3037,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 13,
-                char_start: 12,
+                byte_end: 13,
+                byte_start: 12,
+                column_end: 14,
+                column_start: 13,
                 line_end: 1,
                 line_start: 1,
             },
@@ -602,8 +3048,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 18,
-                char_start: 14,
+                byte_end: 18,
+                byte_start: 14,
+                column_end: 19,
+                column_start: 15,
                 line_end: 1,
                 line_start: 1,
             },
@@ -611,8 +3059,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 19,
-                char_start: 18,
+                byte_end: 19,
+                byte_start: 18,
+                column_end: 20,
+                column_start: 19,
                 line_end: 1,
                 line_start: 1,
             },
@@ -620,8 +3070,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 20,
-                char_start: 19,
+                byte_end: 20,
+                byte_start: 19,
+                column_end: 21,
+                column_start: 20,
                 line_end: 1,
                 line_start: 1,
             },
@@ -629,8 +3081,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 21,
-                char_start: 20,
+                byte_end: 21,
+                byte_start: 20,
+                column_end: 22,
+                column_start: 21,
                 line_end: 1,
                 line_start: 1,
             },
@@ -638,8 +3092,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 22,
-                char_start: 21,
+                byte_end: 22,
+                byte_start: 21,
+                column_end: 23,
+                column_start: 22,
                 line_end: 1,
                 line_start: 1,
             },
@@ -647,8 +3103,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 24,
-                char_start: 23,
+                byte_end: 24,
+                byte_start: 23,
+                column_end: 25,
+                column_start: 24,
                 line_end: 1,
                 line_start: 1,
             },
@@ -656,8 +3114,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 29,
-                char_start: 25,
+                byte_end: 29,
+                byte_start: 25,
+                column_end: 30,
+                column_start: 26,
                 line_end: 1,
                 line_start: 1,
             },
@@ -665,8 +3125,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 31,
-                char_start: 30,
+                byte_end: 31,
+                byte_start: 30,
+                column_end: 32,
+                column_start: 31,
                 line_end: 1,
                 line_start: 1,
             },
@@ -674,8 +3136,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 36,
-                char_start: 32,
+                byte_end: 36,
+                byte_start: 32,
+                column_end: 37,
+                column_start: 33,
                 line_end: 1,
                 line_start: 1,
             },
@@ -683,8 +3147,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 37,
-                char_start: 36,
+                byte_end: 37,
+                byte_start: 36,
+                column_end: 38,
+                column_start: 37,
                 line_end: 1,
                 line_start: 1,
             },
@@ -692,8 +3158,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 38,
-                char_start: 37,
+                byte_end: 38,
+                byte_start: 37,
+                column_end: 39,
+                column_start: 38,
                 line_end: 1,
                 line_start: 1,
             },
@@ -701,8 +3169,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 39,
-                char_start: 38,
+                byte_end: 39,
+                byte_start: 38,
+                column_end: 40,
+                column_start: 39,
                 line_end: 1,
                 line_start: 1,
             },
@@ -710,8 +3180,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 40,
-                char_start: 39,
+                byte_end: 40,
+                byte_start: 39,
+                column_end: 41,
+                column_start: 40,
                 line_end: 1,
                 line_start: 1,
             },
@@ -719,8 +3191,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 42,
-                char_start: 41,
+                byte_end: 42,
+                byte_start: 41,
+                column_end: 43,
+                column_start: 42,
                 line_end: 1,
                 line_start: 1,
             },
@@ -728,8 +3202,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 45,
-                char_start: 42,
+                byte_end: 45,
+                byte_start: 42,
+                column_end: 46,
+                column_start: 43,
                 line_end: 1,
                 line_start: 1,
             },
@@ -746,8 +3222,10 @@ mod tests {
         let mut expected_lexed_tokens: Vec<LexerElement> = Vec::new();
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 3,
-                char_start: 0,
+                byte_end: 3,
+                byte_start: 0,
+                column_end: 4,
+                column_start: 1,
                 line_end: 1,
                 line_start: 1,
             },
@@ -755,8 +3233,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 4,
-                char_start: 3,
+                byte_end: 4,
+                byte_start: 3,
+                column_end: 5,
+                column_start: 4,
                 line_end: 1,
                 line_start: 1,
             },
@@ -764,8 +3244,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 6,
-                char_start: 5,
+                byte_end: 6,
+                byte_start: 5,
+                column_end: 7,
+                column_start: 6,
                 line_end: 1,
                 line_start: 1,
             },
@@ -773,8 +3255,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 8,
-                char_start: 7,
+                byte_end: 8,
+                byte_start: 7,
+                column_end: 9,
+                column_start: 8,
                 line_end: 1,
                 line_start: 1,
             },
@@ -782,8 +3266,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 9,
-                char_start: 8,
+                byte_end: 9,
+                byte_start: 8,
+                column_end: 10,
+                column_start: 9,
                 line_end: 1,
                 line_start: 1,
             },
@@ -791,8 +3277,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 11,
-                char_start: 10,
+                byte_end: 11,
+                byte_start: 10,
+                column_end: 12,
+                column_start: 11,
                 line_end: 1,
                 line_start: 1,
             },
@@ -800,8 +3288,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 13,
-                char_start: 11,
+                byte_end: 13,
+                byte_start: 11,
+                column_end: 14,
+                column_start: 12,
                 line_end: 1,
                 line_start: 1,
             },
@@ -809,8 +3299,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 14,
-                char_start: 13,
+                byte_end: 14,
+                byte_start: 13,
+                column_end: 15,
+                column_start: 14,
                 line_end: 1,
                 line_start: 1,
             },
@@ -818,8 +3310,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 16,
-                char_start: 15,
+                byte_end: 16,
+                byte_start: 15,
+                column_end: 17,
+                column_start: 16,
                 line_end: 1,
                 line_start: 1,
             },
@@ -827,8 +3321,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 18,
-                char_start: 16,
+                byte_end: 18,
+                byte_start: 16,
+                column_end: 19,
+                column_start: 17,
                 line_end: 1,
                 line_start: 1,
             },
@@ -836,8 +3332,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 19,
-                char_start: 18,
+                byte_end: 19,
+                byte_start: 18,
+                column_end: 20,
+                column_start: 19,
                 line_end: 1,
                 line_start: 1,
             },
@@ -845,8 +3343,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 21,
-                char_start: 20,
+                byte_end: 21,
+                byte_start: 20,
+                column_end: 22,
+                column_start: 21,
                 line_end: 1,
                 line_start: 1,
             },
@@ -854,8 +3354,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 23,
-                char_start: 22,
+                byte_end: 23,
+                byte_start: 22,
+                column_end: 24,
+                column_start: 23,
                 line_end: 1,
                 line_start: 1,
             },
@@ -863,8 +3365,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 25,
-                char_start: 24,
+                byte_end: 25,
+                byte_start: 24,
+                column_end: 26,
+                column_start: 25,
                 line_end: 1,
                 line_start: 1,
             },
@@ -872,8 +3376,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 26,
-                char_start: 25,
+                byte_end: 26,
+                byte_start: 25,
+                column_end: 27,
+                column_start: 26,
                 line_end: 1,
                 line_start: 1,
             },
@@ -881,8 +3387,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 31,
-                char_start: 27,
+                byte_end: 31,
+                byte_start: 27,
+                column_end: 32,
+                column_start: 28,
                 line_end: 1,
                 line_start: 1,
             },
@@ -890,8 +3398,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 32,
-                char_start: 31,
+                byte_end: 32,
+                byte_start: 31,
+                column_end: 33,
+                column_start: 32,
                 line_end: 1,
                 line_start: 1,
             },
@@ -899,8 +3409,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 33,
-                char_start: 32,
+                byte_end: 33,
+                byte_start: 32,
+                column_end: 34,
+                column_start: 33,
                 line_end: 1,
                 line_start: 1,
             },
@@ -908,8 +3420,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 34,
-                char_start: 33,
+                byte_end: 34,
+                byte_start: 33,
+                column_end: 35,
+                column_start: 34,
                 line_end: 1,
                 line_start: 1,
             },
@@ -917,8 +3431,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 35,
-                char_start: 34,
+                byte_end: 35,
+                byte_start: 34,
+                column_end: 36,
+                column_start: 35,
                 line_end: 1,
                 line_start: 1,
             },
@@ -926,8 +3442,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 36,
-                char_start: 35,
+                byte_end: 36,
+                byte_start: 35,
+                column_end: 37,
+                column_start: 36,
                 line_end: 1,
                 line_start: 1,
             },
@@ -935,8 +3453,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 37,
-                char_start: 36,
+                byte_end: 37,
+                byte_start: 36,
+                column_end: 38,
+                column_start: 37,
                 line_end: 1,
                 line_start: 1,
             },
@@ -944,8 +3464,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 42,
-                char_start: 38,
+                byte_end: 42,
+                byte_start: 38,
+                column_end: 43,
+                column_start: 39,
                 line_end: 1,
                 line_start: 1,
             },
@@ -953,8 +3475,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 43,
-                char_start: 42,
+                byte_end: 43,
+                byte_start: 42,
+                column_end: 44,
+                column_start: 43,
                 line_end: 1,
                 line_start: 1,
             },
@@ -962,8 +3486,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 52,
-                char_start: 43,
+                byte_end: 53,
+                byte_start: 43,
+                column_end: 54,
+                column_start: 44,
                 line_end: 1,
                 line_start: 1,
             },
@@ -971,8 +3497,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 54,
-                char_start: 53,
+                byte_end: 54,
+                byte_start: 53,
+                column_end: 55,
+                column_start: 54,
                 line_end: 1,
                 line_start: 1,
             },
@@ -980,8 +3508,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 55,
-                char_start: 54,
+                byte_end: 55,
+                byte_start: 54,
+                column_end: 56,
+                column_start: 55,
                 line_end: 1,
                 line_start: 1,
             },
@@ -989,8 +3519,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 57,
-                char_start: 56,
+                byte_end: 57,
+                byte_start: 56,
+                column_end: 58,
+                column_start: 57,
                 line_end: 1,
                 line_start: 1,
             },
@@ -998,8 +3530,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 58,
-                char_start: 57,
+                byte_end: 58,
+                byte_start: 57,
+                column_end: 59,
+                column_start: 58,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1007,8 +3541,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 61,
-                char_start: 59,
+                byte_end: 61,
+                byte_start: 59,
+                column_end: 62,
+                column_start: 60,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1016,8 +3552,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 63,
-                char_start: 62,
+                byte_end: 63,
+                byte_start: 62,
+                column_end: 64,
+                column_start: 63,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1025,8 +3563,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 64,
-                char_start: 63,
+                byte_end: 64,
+                byte_start: 63,
+                column_end: 65,
+                column_start: 64,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1034,8 +3574,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 65,
-                char_start: 64,
+                byte_end: 65,
+                byte_start: 64,
+                column_end: 66,
+                column_start: 65,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1043,8 +3585,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 67,
-                char_start: 66,
+                byte_end: 67,
+                byte_start: 66,
+                column_end: 68,
+                column_start: 67,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1052,8 +3596,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 69,
-                char_start: 68,
+                byte_end: 69,
+                byte_start: 68,
+                column_end: 70,
+                column_start: 69,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1061,8 +3607,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 73,
-                char_start: 70,
+                byte_end: 73,
+                byte_start: 70,
+                column_end: 74,
+                column_start: 71,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1070,8 +3618,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 74,
-                char_start: 73,
+                byte_end: 74,
+                byte_start: 73,
+                column_end: 75,
+                column_start: 74,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1079,8 +3629,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 82,
-                char_start: 75,
+                byte_end: 82,
+                byte_start: 75,
+                column_end: 83,
+                column_start: 76,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1088,8 +3640,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 84,
-                char_start: 83,
+                byte_end: 84,
+                byte_start: 83,
+                column_end: 85,
+                column_start: 84,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1097,8 +3651,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 85,
-                char_start: 84,
+                byte_end: 85,
+                byte_start: 84,
+                column_end: 86,
+                column_start: 85,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1106,8 +3662,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 88,
-                char_start: 86,
+                byte_end: 88,
+                byte_start: 86,
+                column_end: 89,
+                column_start: 87,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1115,8 +3673,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 90,
-                char_start: 89,
+                byte_end: 90,
+                byte_start: 89,
+                column_end: 91,
+                column_start: 90,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1124,8 +3684,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 91,
-                char_start: 90,
+                byte_end: 91,
+                byte_start: 90,
+                column_end: 92,
+                column_start: 91,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1133,8 +3695,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 93,
-                char_start: 92,
+                byte_end: 93,
+                byte_start: 92,
+                column_end: 94,
+                column_start: 93,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1142,8 +3706,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 94,
-                char_start: 93,
+                byte_end: 94,
+                byte_start: 93,
+                column_end: 95,
+                column_start: 94,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1151,8 +3717,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 96,
-                char_start: 95,
+                byte_end: 96,
+                byte_start: 95,
+                column_end: 97,
+                column_start: 96,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1160,8 +3728,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 99,
-                char_start: 97,
+                byte_end: 99,
+                byte_start: 97,
+                column_end: 100,
+                column_start: 98,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1169,8 +3739,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 101,
-                char_start: 100,
+                byte_end: 101,
+                byte_start: 100,
+                column_end: 102,
+                column_start: 101,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1178,8 +3750,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 102,
-                char_start: 101,
+                byte_end: 102,
+                byte_start: 101,
+                column_end: 103,
+                column_start: 102,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1187,8 +3761,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 104,
-                char_start: 103,
+                byte_end: 104,
+                byte_start: 103,
+                column_end: 105,
+                column_start: 104,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1196,8 +3772,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 107,
-                char_start: 105,
+                byte_end: 107,
+                byte_start: 105,
+                column_end: 108,
+                column_start: 106,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1205,8 +3783,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 109,
-                char_start: 108,
+                byte_end: 109,
+                byte_start: 108,
+                column_end: 110,
+                column_start: 109,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1214,8 +3794,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 110,
-                char_start: 109,
+                byte_end: 110,
+                byte_start: 109,
+                column_end: 111,
+                column_start: 110,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1223,8 +3805,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 112,
-                char_start: 111,
+                byte_end: 112,
+                byte_start: 111,
+                column_end: 113,
+                column_start: 112,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1232,8 +3816,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 115,
-                char_start: 113,
+                byte_end: 115,
+                byte_start: 113,
+                column_end: 116,
+                column_start: 114,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1241,8 +3827,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 117,
-                char_start: 116,
+                byte_end: 117,
+                byte_start: 116,
+                column_end: 118,
+                column_start: 117,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1250,8 +3838,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 118,
-                char_start: 117,
+                byte_end: 118,
+                byte_start: 117,
+                column_end: 119,
+                column_start: 118,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1259,8 +3849,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 120,
-                char_start: 119,
+                byte_end: 120,
+                byte_start: 119,
+                column_end: 121,
+                column_start: 120,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1268,8 +3860,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 123,
-                char_start: 121,
+                byte_end: 123,
+                byte_start: 121,
+                column_end: 124,
+                column_start: 122,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1277,8 +3871,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 125,
-                char_start: 124,
+                byte_end: 125,
+                byte_start: 124,
+                column_end: 126,
+                column_start: 125,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1286,8 +3882,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 126,
-                char_start: 125,
+                byte_end: 126,
+                byte_start: 125,
+                column_end: 127,
+                column_start: 126,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1295,8 +3893,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 128,
-                char_start: 127,
+                byte_end: 128,
+                byte_start: 127,
+                column_end: 129,
+                column_start: 128,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1304,8 +3904,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 130,
-                char_start: 129,
+                byte_end: 130,
+                byte_start: 129,
+                column_end: 131,
+                column_start: 130,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1313,8 +3915,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 132,
-                char_start: 131,
+                byte_end: 132,
+                byte_start: 131,
+                column_end: 133,
+                column_start: 132,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1322,8 +3926,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 133,
-                char_start: 132,
+                byte_end: 133,
+                byte_start: 132,
+                column_end: 134,
+                column_start: 133,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1331,8 +3937,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 135,
-                char_start: 134,
+                byte_end: 135,
+                byte_start: 134,
+                column_end: 136,
+                column_start: 135,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1340,8 +3948,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 137,
-                char_start: 136,
+                byte_end: 137,
+                byte_start: 136,
+                column_end: 138,
+                column_start: 137,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1349,8 +3959,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 139,
-                char_start: 138,
+                byte_end: 139,
+                byte_start: 138,
+                column_end: 140,
+                column_start: 139,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1358,8 +3970,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 140,
-                char_start: 139,
+                byte_end: 140,
+                byte_start: 139,
+                column_end: 141,
+                column_start: 140,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1367,8 +3981,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 142,
-                char_start: 141,
+                byte_end: 142,
+                byte_start: 141,
+                column_end: 143,
+                column_start: 142,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1376,8 +3992,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 144,
-                char_start: 143,
+                byte_end: 144,
+                byte_start: 143,
+                column_end: 145,
+                column_start: 144,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1385,8 +4003,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 146,
-                char_start: 145,
+                byte_end: 146,
+                byte_start: 145,
+                column_end: 147,
+                column_start: 146,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1394,8 +4014,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 147,
-                char_start: 146,
+                byte_end: 147,
+                byte_start: 146,
+                column_end: 148,
+                column_start: 147,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1403,8 +4025,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 149,
-                char_start: 148,
+                byte_end: 149,
+                byte_start: 148,
+                column_end: 150,
+                column_start: 149,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1412,8 +4036,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 152,
-                char_start: 150,
+                byte_end: 152,
+                byte_start: 150,
+                column_end: 153,
+                column_start: 151,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1421,8 +4047,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 154,
-                char_start: 153,
+                byte_end: 154,
+                byte_start: 153,
+                column_end: 155,
+                column_start: 154,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1430,8 +4058,10 @@ mod tests {
         });
         expected_lexed_tokens.push(LexerElement {
             position: LexerPosition {
-                char_end: 157,
-                char_start: 154,
+                byte_end: 157,
+                byte_start: 154,
+                column_end: 158,
+                column_start: 155,
                 line_end: 1,
                 line_start: 1,
             },
@@ -1440,6 +4070,105 @@ mod tests {
         assert_eq!(lexed_tokens, expected_lexed_tokens);
     }
 
+    #[test]
+    fn test_lex_positions() {
+        let lexed_tokens = Template::new(
+            "One\n\ttwo\n{{ first }}\n{% echo(\"a\nb\") %}\n\n{{ last }}".to_string(),
+            None,
+        )
+        .lex()
+        .unwrap();
+        let positions: Vec<(LexerToken, usize, usize, usize, usize)> = lexed_tokens
+            .into_iter()
+            .map(|element| {
+                (
+                    element.token,
+                    element.position.line_start(),
+                    element.position.column_start(),
+                    element.position.line_end(),
+                    element.position.column_end(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                (LexerToken::Inline("One\n\ttwo\n".to_string()), 1, 1, 3, 1),
+                (LexerToken::OpenTagWithEcho, 3, 1, 3, 4),
+                (LexerToken::Variable("first".to_string()), 3, 4, 3, 9),
+                (LexerToken::CloseTagWithEcho, 3, 9, 3, 12),
+                (LexerToken::Inline("\n".to_string()), 3, 12, 4, 1),
+                (LexerToken::OpenTag, 4, 1, 4, 4),
+                (LexerToken::Call("echo".to_string()), 4, 4, 4, 8),
+                (LexerToken::OpenParenthesis, 4, 8, 4, 9),
+                (
+                    LexerToken::DoubleQuotedString("a\nb".to_string()),
+                    4,
+                    9,
+                    5,
+                    3
+                ),
+                (LexerToken::CloseParenthesis, 5, 3, 5, 4),
+                (LexerToken::CloseTag, 5, 4, 5, 7),
+                (LexerToken::Inline("\n\n".to_string()), 5, 7, 7, 1),
+                (LexerToken::OpenTagWithEcho, 7, 1, 7, 4),
+                (LexerToken::Variable("last".to_string()), 7, 4, 7, 8),
+                (LexerToken::CloseTagWithEcho, 7, 8, 7, 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_unicode() {
+        let lexed_tokens = Template::new("Grüße {{ 'é😀' }}\nñ{% straße = 1 %}".to_string(), None)
+            .lex()
+            .unwrap();
+        let positions: Vec<(LexerToken, usize, usize, usize, usize)> = lexed_tokens
+            .into_iter()
+            .map(|element| {
+                (
+                    element.token,
+                    element.position.byte_start(),
+                    element.position.byte_end(),
+                    element.position.column_start(),
+                    element.position.column_end(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                (LexerToken::Inline("Grüße ".to_string()), 0, 8, 1, 7),
+                (LexerToken::OpenTagWithEcho, 8, 11, 7, 10),
+                (
+                    LexerToken::SingleQuotedString("é😀".to_string()),
+                    11,
+                    19,
+                    10,
+                    14
+                ),
+                (LexerToken::CloseTagWithEcho, 19, 22, 14, 17),
+                (LexerToken::Inline("\nñ".to_string()), 22, 25, 17, 2),
+                (LexerToken::OpenTag, 25, 28, 2, 5),
+                (LexerToken::Variable("straße".to_string()), 28, 35, 5, 11),
+                (LexerToken::Assign, 36, 37, 12, 13),
+                (LexerToken::Integer("1".to_string()), 38, 39, 14, 15),
+                (LexerToken::CloseTag, 39, 42, 15, 18),
+            ]
+        );
+
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("café".to_string(), DataType::from("☕"));
+        let template = Template::new(
+            "Ça {{ café }} {{ \"日本\" + café }}🎉".to_string(),
+            Some(data),
+        );
+        assert_eq!(template.process().unwrap(), "Ça ☕ 日本☕🎉");
+
+        let template = Template::new("{{ 1 }}😀".to_string(), None);
+        assert_eq!(template.process().unwrap(), "1😀");
+    }
+
     #[test]
     fn test_set_datum() {}
 
@@ -1450,5 +4179,119 @@ mod tests {
     fn test_set_form() {}
 
     #[test]
-    fn test_set_file() {}
+    fn test_set_file() {
+        /// Directory of its own for every run, removed again even when an assertion fails.
+        struct TemporaryDirectory(std::path::PathBuf);
+
+        impl Drop for TemporaryDirectory {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_dir_all(&self.0);
+            }
+        }
+
+        let nanoseconds = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let directory = TemporaryDirectory(std::env::temp_dir().join(format!(
+            "milstian-templates-test-set-file-{}-{}",
+            std::process::id(),
+            nanoseconds
+        )));
+        let root = directory.0.clone();
+        std::fs::create_dir_all(root.join("partials")).unwrap();
+        std::fs::write(
+            root.join("partials").join("user.html"),
+            "<li>{{ name }}{% if admin %} (admin){% endif %}</li>",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("page.html"),
+            "<ul>{% foreach (users as user) %}{% include 'partials/user.html' with user %}{% endforeach %}</ul>{% title = 'Users'; %}{% include \"partials/title.html\" %}",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("partials").join("title.html"),
+            "<h1>{{ title }} ({{ users | length }})</h1>",
+        )
+        .unwrap();
+
+        let mut ada: BTreeMap<String, DataType> = BTreeMap::new();
+        ada.insert("name".to_string(), DataType::from("Ada"));
+        ada.insert("admin".to_string(), DataType::from(true));
+        let mut bob: BTreeMap<String, DataType> = BTreeMap::new();
+        bob.insert("name".to_string(), DataType::from("Bob"));
+        bob.insert("admin".to_string(), DataType::from(false));
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert(
+            "users".to_string(),
+            DataType::List(vec![DataType::Map(ada), DataType::Map(bob)]),
+        );
+        let mut template = Template::new(
+            std::fs::read_to_string(root.join("page.html")).unwrap(),
+            Some(data),
+        );
+        template.set_loader(loader::FileSystemLoader::new(root.clone()));
+        assert_eq!(
+            template.process().unwrap(),
+            "<ul><li>Ada (admin)</li><li>Bob</li></ul><h1>Users (2)</h1>"
+        );
+
+        let loader = loader::FileSystemLoader::new(root.join("partials"));
+        assert!(loader::TemplateLoader::load(&loader, "user.html").is_ok());
+        assert!(loader::TemplateLoader::load(&loader, "../page.html")
+            .unwrap_err()
+            .to_string()
+            .contains("Template name outside of loader root"));
+        assert!(loader::TemplateLoader::load(&loader, "missing.html")
+            .unwrap_err()
+            .to_string()
+            .contains("Failed to read template: missing.html"));
+
+        let mut template = Template::new(
+            "{% include 'partials/user.html' with 'Ada' %}".to_string(),
+            None,
+        );
+        template.set_loader(loader::FileSystemLoader::new(root.clone()));
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("cannot include with string as context"));
+
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert("self.html", "{% include 'self.html' %}");
+        let mut template = Template::new("{% include 'self.html' %}".to_string(), None);
+        template.set_loader(loader);
+        assert!(template
+            .process()
+            .unwrap_err()
+            .to_string()
+            .contains("Maximum include depth exceeded: self.html"));
+
+        // Included templates see the variables of every template including them,
+        // innermost first, and their own assignments stay their own
+        let mut loader = loader::MemoryLoader::new();
+        loader.insert(
+            "row.html",
+            "{% foreach (items as item) %}{% include 'cell.html' %}{% endforeach %}",
+        );
+        loader.insert(
+            "cell.html",
+            "[{{ title }} {{ row }}.{{ item }}{% title = 'cell'; row++; %} {{ title }} {{ row }}]",
+        );
+        let mut data: HashMap<String, DataType> = HashMap::new();
+        data.insert("title".to_string(), DataType::from("data"));
+        data.insert("items".to_string(), DataType::from(vec![1, 2]));
+        let mut template = Template::new(
+            "{% foreach (items as row) %}{% include 'row.html' %}{% endforeach %} {{ title }}"
+                .to_string(),
+            Some(data),
+        );
+        template.set_loader(loader);
+        assert_eq!(
+            template.process().unwrap(),
+            "[data 1.1 cell 2][data 1.2 cell 2][data 2.1 cell 3][data 2.2 cell 3] data"
+        );
+    }
 }
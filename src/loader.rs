@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Source of templates referenced by name from other templates, such as the
+/// parent in `{% extends "base.html" %}`.
+///
+/// Loaders are shared by the threads rendering with an environment.
+pub trait TemplateLoader: Send + Sync {
+    fn load(&self, name: &str) -> Result<String, String>;
+}
+
+impl fmt::Debug for dyn TemplateLoader {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("TemplateLoader")
+    }
+}
+
+/// Loader of template files below a root directory, named by their relative path.
+#[derive(Debug)]
+pub struct FileSystemLoader {
+    root: PathBuf,
+}
+
+impl FileSystemLoader {
+    pub fn new<P: Into<PathBuf>>(root: P) -> FileSystemLoader {
+        FileSystemLoader { root: root.into() }
+    }
+}
+
+impl TemplateLoader for FileSystemLoader {
+    fn load(&self, name: &str) -> Result<String, String> {
+        // Names can't reach outside of the root directory
+        let relative = Path::new(name);
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(format!("Template name outside of loader root: {}", name));
+        }
+        fs::read_to_string(self.root.join(relative))
+            .map_err(|error| format!("Failed to read template: {}, error: {}", name, error))
+    }
+}
+
+/// Loader of templates kept in memory by name.
+#[derive(Debug, Default)]
+pub struct MemoryLoader {
+    templates: HashMap<String, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        MemoryLoader {
+            templates: HashMap::new(),
+        }
+    }
+
+    /// Add a template, replacing any earlier template with the same name.
+    pub fn insert(&mut self, name: &str, form: &str) {
+        self.templates.insert(name.to_string(), form.to_string());
+    }
+}
+
+impl TemplateLoader for MemoryLoader {
+    fn load(&self, name: &str) -> Result<String, String> {
+        match self.templates.get(name) {
+            Some(form) => Ok(form.to_string()),
+            None => Err(format!("Template not found: {}", name)),
+        }
+    }
+}
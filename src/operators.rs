@@ -0,0 +1,169 @@
+use ast::BinaryOperator;
+use ast::UnaryOperator;
+use error::TemplateError;
+use std::cmp::Ordering;
+use DataType;
+
+/// Apply a binary operator to two evaluated operands.
+///
+/// `&&` and `||` short-circuit, so the evaluator only calls this for them
+/// when both operands are needed.
+pub fn binary(
+    operator: BinaryOperator,
+    left: &DataType,
+    right: &DataType,
+) -> Result<DataType, TemplateError> {
+    match operator {
+        BinaryOperator::Addition
+        | BinaryOperator::Division
+        | BinaryOperator::Multiplication
+        | BinaryOperator::Subtraction => arithmetic(operator, left, right),
+        BinaryOperator::And => Ok(boolean(left.is_truthy() && right.is_truthy())),
+        BinaryOperator::Or => Ok(boolean(left.is_truthy() || right.is_truthy())),
+        BinaryOperator::Equals => Ok(boolean(equals(left, right))),
+        BinaryOperator::LesserOrGreaterThan => Ok(boolean(!equals(left, right))),
+        BinaryOperator::GreaterOrEqualThan => {
+            compare(operator, left, right).map(|ordering| boolean(ordering != Ordering::Less))
+        }
+        BinaryOperator::GreaterThan => {
+            compare(operator, left, right).map(|ordering| boolean(ordering == Ordering::Greater))
+        }
+        BinaryOperator::LesserOrEqualThan => {
+            compare(operator, left, right).map(|ordering| boolean(ordering != Ordering::Greater))
+        }
+        BinaryOperator::LesserThan => {
+            compare(operator, left, right).map(|ordering| boolean(ordering == Ordering::Less))
+        }
+    }
+}
+
+/// Apply a prefix operator to an evaluated operand.
+pub fn unary(operator: UnaryOperator, operand: &DataType) -> Result<DataType, TemplateError> {
+    match (operator, operand) {
+        (UnaryOperator::Negation, _) => Ok(boolean(!operand.is_truthy())),
+        (UnaryOperator::Minus, DataType::Float(value)) => Ok(DataType::Float(-value)),
+        (UnaryOperator::Minus, DataType::Integer(value)) => match value.checked_neg() {
+            Some(value) => Ok(DataType::Integer(value)),
+            None => Err(TemplateError::render(format!(
+                "Integer overflow when negating {}",
+                value
+            ))),
+        },
+        (UnaryOperator::Minus, _) => Err(TemplateError::type_mismatch(format!(
+            "Type mismatch: cannot apply {} to {}",
+            operator,
+            operand.type_name()
+        ))),
+    }
+}
+
+pub fn boolean(value: bool) -> DataType {
+    DataType::Bool(value)
+}
+
+fn arithmetic(
+    operator: BinaryOperator,
+    left: &DataType,
+    right: &DataType,
+) -> Result<DataType, TemplateError> {
+    match (left, right) {
+        (DataType::Integer(a), DataType::Integer(b)) => {
+            let result = match operator {
+                BinaryOperator::Addition => a.checked_add(*b),
+                BinaryOperator::Subtraction => a.checked_sub(*b),
+                BinaryOperator::Multiplication => a.checked_mul(*b),
+                _ => {
+                    if *b == 0 {
+                        return Err(TemplateError::render("Division by zero".to_string()));
+                    }
+                    // Division only stays integral when there is no remainder
+                    match a.checked_rem(*b) {
+                        Some(0) => a.checked_div(*b),
+                        Some(_) => return Ok(DataType::Float(*a as f64 / *b as f64)),
+                        None => None,
+                    }
+                }
+            };
+            match result {
+                Some(value) => Ok(DataType::Integer(value)),
+                None => Err(TemplateError::render(format!(
+                    "Integer overflow in {} {} {}",
+                    a, operator, b
+                ))),
+            }
+        }
+        _ if operator == BinaryOperator::Addition && left.as_str().is_some() => {
+            match (left.as_str(), right.as_str()) {
+                (Some(a), Some(b)) => Ok(DataType::String(format!("{}{}", a, b))),
+                _ => Err(mismatch(operator, left, right)),
+            }
+        }
+        _ => match (number(left), number(right)) {
+            (Some(a), Some(b)) => match operator {
+                BinaryOperator::Addition => Ok(DataType::Float(a + b)),
+                BinaryOperator::Subtraction => Ok(DataType::Float(a - b)),
+                BinaryOperator::Multiplication => Ok(DataType::Float(a * b)),
+                _ => {
+                    if b == 0.0 {
+                        return Err(TemplateError::render("Division by zero".to_string()));
+                    }
+                    Ok(DataType::Float(a / b))
+                }
+            },
+            _ => Err(mismatch(operator, left, right)),
+        },
+    }
+}
+
+fn compare(
+    operator: BinaryOperator,
+    left: &DataType,
+    right: &DataType,
+) -> Result<Ordering, TemplateError> {
+    match (left, right) {
+        (DataType::Integer(a), DataType::Integer(b)) => Ok(a.cmp(b)),
+        _ => match (number(left), number(right)) {
+            (Some(a), Some(b)) => match a.partial_cmp(&b) {
+                Some(ordering) => Ok(ordering),
+                None => Err(TemplateError::type_mismatch(format!(
+                    "Cannot compare {} and {}",
+                    a, b
+                ))),
+            },
+            _ => match (left.as_str(), right.as_str()) {
+                (Some(a), Some(b)) => Ok(a.cmp(b)),
+                _ => Err(mismatch(operator, left, right)),
+            },
+        },
+    }
+}
+
+/// Numbers compare by value across integers and floats, strings by their text
+/// whether they are safe or not, other values must be identical.
+fn equals(left: &DataType, right: &DataType) -> bool {
+    match (left, right) {
+        (DataType::Integer(_), DataType::Float(_)) | (DataType::Float(_), DataType::Integer(_)) => {
+            number(left) == number(right)
+        }
+        (DataType::SafeString(_), DataType::String(_))
+        | (DataType::String(_), DataType::SafeString(_)) => left.as_str() == right.as_str(),
+        _ => left == right,
+    }
+}
+
+fn number(value: &DataType) -> Option<f64> {
+    match value {
+        DataType::Float(value) => Some(*value),
+        DataType::Integer(value) => Some(*value as f64),
+        _ => None,
+    }
+}
+
+fn mismatch(operator: BinaryOperator, left: &DataType, right: &DataType) -> TemplateError {
+    TemplateError::type_mismatch(format!(
+        "Type mismatch: cannot apply {} to {} and {}",
+        operator,
+        left.type_name(),
+        right.type_name()
+    ))
+}
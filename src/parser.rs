@@ -0,0 +1,814 @@
+use ast::BinaryOperator;
+use ast::Branch;
+use ast::Expression;
+use ast::ExpressionKind;
+use ast::Statement;
+use ast::StatementKind;
+use ast::UnaryOperator;
+use error::TemplateError;
+use std::sync::Arc;
+use tokens;
+use DataType;
+use LexerElement;
+use LexerPosition;
+use LexerToken;
+
+/// Recursive-descent parser turning the lexer output into a syntax tree.
+///
+/// Tag delimiters (`{% ` and ` %}`) only separate statements, so a block or
+/// control structure may span several tags with inline text in between.
+///
+/// Binary operators are parsed by precedence climbing, from lowest to highest
+/// precedence, all of them left-associative:
+///
+/// | Precedence | Operators            |
+/// |------------|----------------------|
+/// | 1          | `\|\|`               |
+/// | 2          | `&&`                 |
+/// | 3          | `==` `<>`            |
+/// | 4          | `<` `<=` `>` `>=`    |
+/// | 5          | `+` `-`              |
+/// | 6          | `*` `/`              |
+///
+/// The prefix operators `!` and `-` bind tighter than any binary operator,
+/// member access `user.name`, indexing `items[0]` and filters
+/// `title | upper | truncate(40)` bind tighter still.
+///
+/// Control structures come in two forms, with bodies in curly brackets or
+/// with bodies closed by an end keyword:
+///
+/// ```text
+/// {% if a > b { echo(a); } else { echo(b); } %}
+/// {% if user %}...{% elseif guest %}...{% else %}...{% endif %}
+/// {% foreach (items as item) { echo(item); } %}
+/// {% foreach (map as key => value) %}...{% endforeach %}
+/// {% block content %}...{% endblock %}
+/// {% include "partials/user.html" with user %}
+/// ```
+pub struct Parser {
+    /// Names of the blocks defined so far, which must be unique in a template
+    blocks: Vec<String>,
+    /// Number of bodies the parser is in, `extends` is only allowed outside of them
+    depth: usize,
+    elements: Vec<LexerElement>,
+    index: usize,
+    open_tag: Option<LexerPosition>,
+}
+
+impl Parser {
+    pub fn new(elements: Vec<LexerElement>) -> Parser {
+        Parser {
+            blocks: Vec::new(),
+            depth: 0,
+            elements,
+            index: 0,
+            open_tag: None,
+        }
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Statement>, TemplateError> {
+        let statements = self.parse_statements(&|_token| false)?;
+        if let Some(element) = self.elements.get(self.index) {
+            return Err(unexpected(element, "statement"));
+        }
+        if let Some(position) = &self.open_tag {
+            return Err(TemplateError::parse(
+                "Unexpected end of template, expected close tag".to_string(),
+            )
+            .at(position));
+        }
+        check_extends(&statements)?;
+        Ok(statements)
+    }
+
+    /// Parse statements until end of stream or until `is_terminator` accepts the next token.
+    fn parse_statements(
+        &mut self,
+        is_terminator: &dyn Fn(&LexerToken) -> bool,
+    ) -> Result<Vec<Statement>, TemplateError> {
+        let mut statements: Vec<Statement> = Vec::new();
+        while let Some(token) = self.peek() {
+            if is_terminator(token) {
+                break;
+            }
+            match token {
+                LexerToken::CloseCurlyBracket => {
+                    break;
+                }
+                LexerToken::OpenTag => {
+                    let position = self.advance().position.clone();
+                    self.open_tag = Some(position);
+                }
+                LexerToken::CloseTag => {
+                    self.advance();
+                    self.open_tag = None;
+                }
+                LexerToken::Semicolon => {
+                    self.advance();
+                }
+                _ => {
+                    statements.push(self.parse_statement()?);
+                }
+            }
+        }
+        Ok(statements)
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, TemplateError> {
+        let element = self.advance().clone();
+        if let Some(word) = tokens::get_keyword_word(&element.token) {
+            match self.peek() {
+                Some(LexerToken::Assign)
+                | Some(LexerToken::AddOne)
+                | Some(LexerToken::SubtractOne) => return Err(reserved(&element, word)),
+                _ => {}
+            }
+        }
+        match &element.token {
+            LexerToken::Inline(string) => Ok(Statement {
+                kind: StatementKind::Inline(string.to_string()),
+                position: element.position.clone(),
+            }),
+            LexerToken::OpenTagWithEcho => {
+                let expression = self.parse_expression()?;
+                let end = self.expect(&LexerToken::CloseTagWithEcho, "close tag")?;
+                Ok(Statement {
+                    kind: StatementKind::Echo(expression),
+                    position: element.position.span(&end),
+                })
+            }
+            LexerToken::Block => self.parse_named_block(element.position),
+            LexerToken::Else | LexerToken::ElseIf | LexerToken::EndIf => {
+                Err(unmatched(&element, "if"))
+            }
+            LexerToken::EndBlock => Err(unmatched(&element, "block")),
+            LexerToken::EndForEach => Err(unmatched(&element, "foreach")),
+            LexerToken::Extends => {
+                // The parent is found before rendering, so it can't depend on conditions or loops
+                if self.depth > 0 {
+                    return Err(TemplateError::parse(
+                        "Unexpected extends inside a body, it must be at the top level of the template"
+                            .to_string(),
+                    )
+                    .at(&element.position));
+                }
+                let name = self.parse_expression()?;
+                self.end_statement()?;
+                Ok(Statement {
+                    position: element.position.span(&name.position),
+                    kind: StatementKind::Extends(name),
+                })
+            }
+            LexerToken::ForEach => self.parse_foreach(element.position),
+            LexerToken::Include => {
+                let name = self.parse_expression()?;
+                let mut end = name.position.clone();
+                let mut context: Option<Expression> = None;
+                if let Some(LexerToken::With) = self.peek() {
+                    self.advance();
+                    let expression = self.parse_expression()?;
+                    end = expression.position.clone();
+                    context = Some(expression);
+                }
+                self.end_statement()?;
+                Ok(Statement {
+                    kind: StatementKind::Include { name, context },
+                    position: element.position.span(&end),
+                })
+            }
+            LexerToken::If => self.parse_if(element.position),
+            LexerToken::OpenCurlyBracket => {
+                self.depth += 1;
+                let statements = self.parse_statements(&|_token| false)?;
+                self.depth -= 1;
+                let end = self.expect(&LexerToken::CloseCurlyBracket, "}")?;
+                Ok(Statement {
+                    kind: StatementKind::Block(statements),
+                    position: element.position.span(&end),
+                })
+            }
+            LexerToken::Variable(name) => {
+                let (kind, end) = match self.peek() {
+                    Some(LexerToken::Assign) => {
+                        self.advance();
+                        let value = self.parse_expression()?;
+                        let end = value.position.clone();
+                        (
+                            StatementKind::Assign {
+                                name: name.to_string(),
+                                value,
+                            },
+                            end,
+                        )
+                    }
+                    Some(LexerToken::AddOne) | Some(LexerToken::SubtractOne) => {
+                        let step_element = self.advance();
+                        let step = if step_element.token == LexerToken::AddOne {
+                            1
+                        } else {
+                            -1
+                        };
+                        (
+                            StatementKind::Increment {
+                                name: name.to_string(),
+                                step,
+                            },
+                            step_element.position.clone(),
+                        )
+                    }
+                    _ => {
+                        self.index -= 1;
+                        return self.parse_expression_statement();
+                    }
+                };
+                self.end_statement()?;
+                Ok(Statement {
+                    kind,
+                    position: element.position.span(&end),
+                })
+            }
+            _ => {
+                self.index -= 1;
+                self.parse_expression_statement()
+            }
+        }
+    }
+
+    fn parse_expression_statement(&mut self) -> Result<Statement, TemplateError> {
+        let expression = self.parse_expression()?;
+        self.end_statement()?;
+        Ok(Statement {
+            position: expression.position.clone(),
+            kind: StatementKind::Expression(expression),
+        })
+    }
+
+    fn parse_named_block(&mut self, start: LexerPosition) -> Result<Statement, TemplateError> {
+        let name = self.expect_variable()?;
+        if self.blocks.contains(&name) {
+            return Err(
+                TemplateError::parse(format!("Block defined twice: {}", name))
+                    .at(&self.elements[self.index - 1].position),
+            );
+        }
+        self.blocks.push(name.to_string());
+        let with_brackets = self.peek() == Some(&LexerToken::OpenCurlyBracket);
+        let (statements, mut end) =
+            self.parse_body(with_brackets, &|token| *token == LexerToken::EndBlock)?;
+        if !with_brackets {
+            end = self.expect(&LexerToken::EndBlock, "endblock")?;
+            // The name may be repeated after endblock
+            if let Some(LexerElement {
+                token: LexerToken::Variable(end_name),
+                position,
+            }) = self.elements.get(self.index)
+            {
+                if *end_name != name {
+                    return Err(TemplateError::parse(format!(
+                        "Unexpected endblock name: {}, expected {}",
+                        end_name, name
+                    ))
+                    .at(position));
+                }
+                end = position.clone();
+                self.index += 1;
+            }
+        }
+        Ok(Statement {
+            kind: StatementKind::NamedBlock {
+                name,
+                statements: Arc::new(statements),
+            },
+            position: start.span(&end),
+        })
+    }
+
+    fn parse_foreach(&mut self, start: LexerPosition) -> Result<Statement, TemplateError> {
+        self.expect(&LexerToken::OpenParenthesis, "(")?;
+        let subject = self.parse_expression()?;
+        self.expect(&LexerToken::As, "as")?;
+        let mut key: Option<String> = None;
+        let mut value = self.expect_variable()?;
+        if let Some(LexerToken::DoubleArrow) = self.peek() {
+            self.advance();
+            key = Some(value);
+            value = self.expect_variable()?;
+        }
+        self.expect(&LexerToken::CloseParenthesis, ")")?;
+        let with_brackets = self.peek() == Some(&LexerToken::OpenCurlyBracket);
+        let (statements, mut end) =
+            self.parse_body(with_brackets, &|token| *token == LexerToken::EndForEach)?;
+        if !with_brackets {
+            end = self.expect(&LexerToken::EndForEach, "endforeach")?;
+        }
+        Ok(Statement {
+            kind: StatementKind::ForEach {
+                subject,
+                key,
+                value,
+                statements,
+            },
+            position: start.span(&end),
+        })
+    }
+
+    fn parse_if(&mut self, start: LexerPosition) -> Result<Statement, TemplateError> {
+        let with_brackets = self.is_block_start();
+        let mut branches: Vec<Branch> = Vec::new();
+        let mut otherwise: Option<Vec<Statement>> = None;
+        let mut end: LexerPosition;
+        loop {
+            let condition = self.parse_expression()?;
+            let (statements, body_end) = self.parse_body(with_brackets, &|token| {
+                *token == LexerToken::ElseIf
+                    || *token == LexerToken::Else
+                    || *token == LexerToken::EndIf
+            })?;
+            branches.push(Branch {
+                condition,
+                statements,
+            });
+            end = body_end;
+            if with_brackets
+                && !self.skip_tags_before(&|token| {
+                    *token == LexerToken::ElseIf || *token == LexerToken::Else
+                })
+            {
+                break;
+            }
+            match self.peek() {
+                Some(LexerToken::ElseIf) => {
+                    self.advance();
+                }
+                Some(LexerToken::Else) => {
+                    self.advance();
+                    // Treat "else if" like "elseif"
+                    if let Some(LexerToken::If) = self.peek() {
+                        self.advance();
+                        continue;
+                    }
+                    let (statements, body_end) =
+                        self.parse_body(with_brackets, &|token| *token == LexerToken::EndIf)?;
+                    otherwise = Some(statements);
+                    end = body_end;
+                    if !with_brackets {
+                        end = self.expect(&LexerToken::EndIf, "endif")?;
+                    }
+                    break;
+                }
+                _ => {
+                    end = self.expect(&LexerToken::EndIf, "elseif, else or endif")?;
+                    break;
+                }
+            }
+        }
+        Ok(Statement {
+            kind: StatementKind::If {
+                branches,
+                otherwise,
+            },
+            position: start.span(&end),
+        })
+    }
+
+    /// Parse the body of a control structure, either inside curly brackets or
+    /// up to the token accepted by `is_end`, which is left for the caller.
+    fn parse_body(
+        &mut self,
+        with_brackets: bool,
+        is_end: &dyn Fn(&LexerToken) -> bool,
+    ) -> Result<(Vec<Statement>, LexerPosition), TemplateError> {
+        self.depth += 1;
+        let result = if with_brackets {
+            self.expect(&LexerToken::OpenCurlyBracket, "{")?;
+            let statements = self.parse_statements(&|_token| false)?;
+            let end = self.expect(&LexerToken::CloseCurlyBracket, "}")?;
+            Ok((statements, end))
+        } else {
+            let statements = self.parse_statements(is_end)?;
+            match self.elements.get(self.index) {
+                Some(element) => Ok((statements, element.position.clone())),
+                None => Err(self.end_of_template("end of block")),
+            }
+        };
+        self.depth -= 1;
+        result
+    }
+
+    /// Whether the body after the upcoming condition is in curly brackets.
+    fn is_block_start(&self) -> bool {
+        for element in &self.elements[self.index..] {
+            match element.token {
+                LexerToken::OpenCurlyBracket => return true,
+                LexerToken::CloseTag
+                | LexerToken::CloseTagWithEcho
+                | LexerToken::Semicolon
+                | LexerToken::CloseCurlyBracket => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Skip close and open tags when they are followed by a token accepted by
+    /// `is_next`, so a closing bracket and the next keyword may be in different tags.
+    fn skip_tags_before(&mut self, is_next: &dyn Fn(&LexerToken) -> bool) -> bool {
+        let mut index = self.index;
+        while let Some(element) = self.elements.get(index) {
+            match element.token {
+                LexerToken::CloseTag | LexerToken::OpenTag => index += 1,
+                _ => break,
+            }
+        }
+        match self.elements.get(index) {
+            Some(element) if is_next(&element.token) => {
+                if index > self.index {
+                    self.open_tag = Some(self.elements[index - 1].position.clone());
+                }
+                self.index = index;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Statements inside code end with a semicolon, a close tag or the end of a block.
+    fn end_statement(&mut self) -> Result<(), TemplateError> {
+        match self.peek() {
+            Some(LexerToken::Semicolon) => {
+                self.advance();
+                Ok(())
+            }
+            Some(LexerToken::CloseTag) | Some(LexerToken::CloseCurlyBracket) | None => Ok(()),
+            Some(_) => Err(unexpected(&self.elements[self.index], "; or close tag")),
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, TemplateError> {
+        self.parse_binary(1)
+    }
+
+    fn parse_binary(&mut self, minimum_precedence: u8) -> Result<Expression, TemplateError> {
+        let mut left = self.parse_unary()?;
+        while let Some((operator, precedence)) = self.peek().and_then(binary_operator) {
+            if precedence < minimum_precedence {
+                break;
+            }
+            let operator_position = self.advance().position.clone();
+            let right = self.parse_binary(precedence + 1)?;
+            let position = left.position.span(&right.position);
+            left = Expression {
+                kind: ExpressionKind::Binary {
+                    left: Box::new(left),
+                    operator,
+                    operator_position,
+                    right: Box::new(right),
+                },
+                position,
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, TemplateError> {
+        let operator = match self.peek() {
+            Some(LexerToken::Negation) => UnaryOperator::Negation,
+            Some(LexerToken::Subtraction) => UnaryOperator::Minus,
+            _ => return self.parse_postfix(),
+        };
+        let start = self.advance().position.clone();
+        let operand = self.parse_unary()?;
+        Ok(Expression {
+            position: start.span(&operand.position),
+            kind: ExpressionKind::Unary {
+                operator,
+                operand: Box::new(operand),
+            },
+        })
+    }
+
+    /// Parse member access, indexing and filters following a primary expression.
+    fn parse_postfix(&mut self) -> Result<Expression, TemplateError> {
+        let mut expression = self.parse_primary()?;
+        loop {
+            let (key, end) = match self.peek() {
+                Some(LexerToken::Pipe) => {
+                    self.advance();
+                    expression = self.parse_filter(expression)?;
+                    continue;
+                }
+                Some(LexerToken::Dot) => {
+                    self.advance();
+                    match self.elements.get(self.index) {
+                        Some(LexerElement {
+                            token: LexerToken::Variable(name),
+                            position,
+                        }) => {
+                            let key = Expression {
+                                kind: ExpressionKind::Literal(DataType::String(name.to_string())),
+                                position: position.clone(),
+                            };
+                            self.index += 1;
+                            (key, position.clone())
+                        }
+                        Some(element) => {
+                            return Err(unexpected(element, "member name"));
+                        }
+                        None => {
+                            return Err(self.end_of_template("member name"));
+                        }
+                    }
+                }
+                Some(LexerToken::OpenSquareBracket) => {
+                    self.advance();
+                    let key = self.parse_expression()?;
+                    let end = self.expect(&LexerToken::CloseSquareBracket, "]")?;
+                    (key, end)
+                }
+                _ => return Ok(expression),
+            };
+            let position = expression.position.span(&end);
+            expression = Expression {
+                kind: ExpressionKind::Index {
+                    subject: Box::new(expression),
+                    key: Box::new(key),
+                },
+                position,
+            };
+        }
+    }
+
+    /// Parse the name and arguments of a filter applied to `subject`.
+    fn parse_filter(&mut self, subject: Expression) -> Result<Expression, TemplateError> {
+        let element = match self.elements.get(self.index) {
+            Some(element) => element.clone(),
+            None => return Err(self.end_of_template("filter")),
+        };
+        self.index += 1;
+        let (name, arguments, end) = match &element.token {
+            LexerToken::Variable(name) => (name.to_string(), Vec::new(), element.position.clone()),
+            LexerToken::Call(name) => {
+                self.expect(&LexerToken::OpenParenthesis, "(")?;
+                let (arguments, end) = self.parse_arguments()?;
+                (name.to_string(), arguments, end)
+            }
+            _ => {
+                return Err(unexpected(&element, "filter"));
+            }
+        };
+        Ok(Expression {
+            position: subject.position.span(&end),
+            kind: ExpressionKind::Filter {
+                arguments,
+                name,
+                name_position: element.position,
+                subject: Box::new(subject),
+            },
+        })
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, TemplateError> {
+        let element = match self.elements.get(self.index) {
+            Some(element) => element.clone(),
+            None => return Err(self.end_of_template("expression")),
+        };
+        self.index += 1;
+        let position = element.position.clone();
+        let kind = match &element.token {
+            LexerToken::DoubleQuotedString(string) | LexerToken::SingleQuotedString(string) => {
+                ExpressionKind::Literal(DataType::String(unescape(string)))
+            }
+            LexerToken::Float(string) => match string.parse::<f64>() {
+                Ok(value) => ExpressionKind::Literal(DataType::Float(value)),
+                Err(error) => {
+                    return Err(TemplateError::parse(format!(
+                        "Invalid float: {}, error: {}",
+                        string, error
+                    ))
+                    .at(&position));
+                }
+            },
+            LexerToken::Integer(string) => match string.parse::<i64>() {
+                Ok(value) => ExpressionKind::Literal(DataType::Integer(value)),
+                Err(error) => {
+                    return Err(TemplateError::parse(format!(
+                        "Invalid integer: {}, error: {}",
+                        string, error
+                    ))
+                    .at(&position));
+                }
+            },
+            LexerToken::True => ExpressionKind::Literal(DataType::Bool(true)),
+            LexerToken::False => ExpressionKind::Literal(DataType::Bool(false)),
+            LexerToken::Null => ExpressionKind::Literal(DataType::Null),
+            LexerToken::Variable(name) => ExpressionKind::Variable(name.to_string()),
+            // Custom syntax calls the native function named like its kind with the matched text
+            LexerToken::Custom(kind, text) => ExpressionKind::Call {
+                name: kind.to_string(),
+                arguments: vec![Expression {
+                    kind: ExpressionKind::Literal(DataType::String(text.to_string())),
+                    position: position.clone(),
+                }],
+            },
+            LexerToken::Call(name) => {
+                let name = name.to_string();
+                self.expect(&LexerToken::OpenParenthesis, "(")?;
+                let (arguments, end) = self.parse_arguments()?;
+                return Ok(Expression {
+                    kind: ExpressionKind::Call { name, arguments },
+                    position: position.span(&end),
+                });
+            }
+            LexerToken::OpenParenthesis => {
+                let mut expression = self.parse_expression()?;
+                let end = self.expect(&LexerToken::CloseParenthesis, ")")?;
+                expression.position = position.span(&end);
+                return Ok(expression);
+            }
+            _ => {
+                return Err(unexpected_name(&element, "expression"));
+            }
+        };
+        Ok(Expression { kind, position })
+    }
+
+    /// Parse comma-separated arguments up to and including the closing parenthesis.
+    fn parse_arguments(&mut self) -> Result<(Vec<Expression>, LexerPosition), TemplateError> {
+        let mut arguments: Vec<Expression> = Vec::new();
+        if let Some(LexerToken::CloseParenthesis) = self.peek() {
+            let end = self.advance().position.clone();
+            return Ok((arguments, end));
+        }
+        loop {
+            arguments.push(self.parse_expression()?);
+            match self.peek() {
+                Some(LexerToken::Comma) => {
+                    self.advance();
+                }
+                _ => {
+                    let end = self.expect(&LexerToken::CloseParenthesis, ", or )")?;
+                    return Ok((arguments, end));
+                }
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&LexerToken> {
+        self.elements.get(self.index).map(|element| &element.token)
+    }
+
+    fn advance(&mut self) -> &LexerElement {
+        self.index += 1;
+        &self.elements[self.index - 1]
+    }
+
+    fn expect_variable(&mut self) -> Result<String, TemplateError> {
+        match self.elements.get(self.index) {
+            Some(LexerElement {
+                token: LexerToken::Variable(name),
+                ..
+            }) => {
+                let name = name.to_string();
+                self.index += 1;
+                Ok(name)
+            }
+            Some(element) => Err(unexpected_name(element, "variable")),
+            None => Err(self.end_of_template("variable")),
+        }
+    }
+
+    fn expect(
+        &mut self,
+        token: &LexerToken,
+        expected: &str,
+    ) -> Result<LexerPosition, TemplateError> {
+        match self.elements.get(self.index) {
+            Some(element) if element.token == *token => {
+                let position = element.position.clone();
+                self.index += 1;
+                Ok(position)
+            }
+            Some(element) => Err(unexpected(element, expected)),
+            None => Err(self.end_of_template(expected)),
+        }
+    }
+
+    /// Error for a template ending where `expected` should follow, placed at its last token.
+    fn end_of_template(&self, expected: &str) -> TemplateError {
+        let error =
+            TemplateError::parse(format!("Unexpected end of template, expected {}", expected));
+        match self.elements.last() {
+            Some(element) => error.at(&element.position),
+            None => error,
+        }
+    }
+}
+
+/// A template extending another is rendered as its parent, so it may only
+/// extend one and everything but its blocks would be ignored.
+fn check_extends(statements: &[Statement]) -> Result<(), TemplateError> {
+    let extends: Vec<&Statement> = statements
+        .iter()
+        .filter(|statement| matches!(statement.kind, StatementKind::Extends(_)))
+        .collect();
+    if extends.is_empty() {
+        return Ok(());
+    }
+    if let Some(statement) = extends.get(1) {
+        return Err(
+            TemplateError::parse("Template extends more than one template".to_string())
+                .at(&statement.position),
+        );
+    }
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Extends(_) | StatementKind::NamedBlock { .. } => {}
+            StatementKind::Inline(text) if text.trim().is_empty() => {}
+            _ => {
+                return Err(TemplateError::parse(
+                    "Unexpected content outside blocks in a template that extends another"
+                        .to_string(),
+                )
+                .at(&statement.position));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Error for `element` found where `expected` should be.
+fn unexpected(element: &LexerElement, expected: &str) -> TemplateError {
+    TemplateError::parse(format!(
+        "Unexpected token: {:?}, expected {}",
+        element.token, expected
+    ))
+    .at(&element.position)
+}
+
+/// Error for a token where a variable or expression should be, telling when
+/// it is a keyword used as a variable name.
+fn unexpected_name(element: &LexerElement, expected: &str) -> TemplateError {
+    match tokens::get_keyword_word(&element.token) {
+        Some(word) => reserved(element, word),
+        None => unexpected(element, expected),
+    }
+}
+
+/// Error for a keyword continuing or ending an `opening` statement that isn't open.
+fn unmatched(element: &LexerElement, opening: &str) -> TemplateError {
+    TemplateError::parse(format!(
+        "Unexpected {} without matching {}",
+        tokens::get_keyword_word(&element.token).unwrap_or_default(),
+        opening
+    ))
+    .at(&element.position)
+}
+
+fn reserved(element: &LexerElement, word: &str) -> TemplateError {
+    TemplateError::parse(format!(
+        "Reserved word cannot be used as a variable name: {}",
+        word
+    ))
+    .at(&element.position)
+}
+
+/// Binary operator and precedence for a token, see the table on `Parser`.
+fn binary_operator(token: &LexerToken) -> Option<(BinaryOperator, u8)> {
+    match token {
+        LexerToken::Or => Some((BinaryOperator::Or, 1)),
+        LexerToken::And => Some((BinaryOperator::And, 2)),
+        LexerToken::Equals => Some((BinaryOperator::Equals, 3)),
+        LexerToken::LesserOrGreaterThan => Some((BinaryOperator::LesserOrGreaterThan, 3)),
+        LexerToken::GreaterOrEqualThan => Some((BinaryOperator::GreaterOrEqualThan, 4)),
+        LexerToken::GreaterThan => Some((BinaryOperator::GreaterThan, 4)),
+        LexerToken::LesserOrEqualThan => Some((BinaryOperator::LesserOrEqualThan, 4)),
+        LexerToken::LesserThan => Some((BinaryOperator::LesserThan, 4)),
+        LexerToken::Addition => Some((BinaryOperator::Addition, 5)),
+        LexerToken::Subtraction => Some((BinaryOperator::Subtraction, 5)),
+        LexerToken::Division => Some((BinaryOperator::Division, 6)),
+        LexerToken::Multiplication => Some((BinaryOperator::Multiplication, 6)),
+        _ => None,
+    }
+}
+
+/// Resolve backslash escapes inside quoted strings.
+fn unescape(string: &str) -> String {
+    let mut result = String::with_capacity(string.len());
+    let mut previous_was_escape = false;
+    for character in string.chars() {
+        if previous_was_escape {
+            match character {
+                'n' => result.push('\n'),
+                'r' => result.push('\r'),
+                't' => result.push('\t'),
+                _ => result.push(character),
+            }
+            previous_was_escape = false;
+        } else if character == '\\' {
+            previous_was_escape = true;
+        } else {
+            result.push(character);
+        }
+    }
+    result
+}
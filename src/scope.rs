@@ -0,0 +1,89 @@
+use DataType;
+use Variable;
+
+/// Runtime variables of a template as a stack of nested scopes.
+///
+/// The outermost scope belongs to the template, loop bodies and blocks push
+/// their own. Template data is never stored here, so assignments can't
+/// change the caller's data.
+pub struct Scope {
+    frames: Vec<Vec<Variable>>,
+}
+
+impl Default for Scope {
+    fn default() -> Scope {
+        Scope::new()
+    }
+}
+
+impl Scope {
+    pub fn new() -> Scope {
+        Scope {
+            frames: vec![Vec::new()],
+        }
+    }
+
+    pub fn push(&mut self) {
+        self.frames.push(Vec::new());
+    }
+
+    pub fn pop(&mut self) {
+        if self.frames.len() > 1 {
+            self.frames.pop();
+        }
+    }
+
+    /// Look up a variable starting from the innermost scope.
+    pub fn get(&self, name: &str) -> Option<&DataType> {
+        self.frames
+            .iter()
+            .rev()
+            .flat_map(|frame| frame.iter())
+            .find(|variable| variable.name == name)
+            .map(|variable| &variable.datum)
+    }
+
+    /// All variables from the outermost scope to the innermost, so later ones
+    /// shadow earlier ones with the same name.
+    pub fn variables(&self) -> impl Iterator<Item = &Variable> {
+        self.frames.iter().flat_map(|frame| frame.iter())
+    }
+
+    /// Declare a variable in the innermost scope, shadowing outer ones.
+    pub fn define(&mut self, name: &str, datum: DataType) {
+        let frame = self.frames.last_mut().unwrap();
+        if let Some(variable) = frame.iter_mut().find(|variable| variable.name == name) {
+            variable.datum = datum;
+            return;
+        }
+        frame.push(Variable {
+            datum,
+            name: name.to_string(),
+        });
+    }
+
+    /// Declare a variable in the template scope, the outermost one, so it
+    /// outlives the loops and blocks it is declared in.
+    pub fn define_outermost(&mut self, name: &str, datum: DataType) {
+        let frame = self.frames.first_mut().unwrap();
+        if let Some(variable) = frame.iter_mut().find(|variable| variable.name == name) {
+            variable.datum = datum;
+            return;
+        }
+        frame.push(Variable {
+            datum,
+            name: name.to_string(),
+        });
+    }
+
+    /// Update the nearest variable with this name, or declare it in the innermost scope.
+    pub fn assign(&mut self, name: &str, datum: DataType) {
+        for frame in self.frames.iter_mut().rev() {
+            if let Some(variable) = frame.iter_mut().find(|variable| variable.name == name) {
+                variable.datum = datum;
+                return;
+            }
+        }
+        self.define(name, datum);
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+use std::sync::OnceLock;
 use LexerElement;
 use LexerPosition;
 use LexerState;
@@ -5,1196 +7,911 @@ use LexerToken;
 use LexerTokenMatchPattern;
 use LexerTokenMatcher;
 
-// Setup lexer patterns here
+/// Words that are keywords instead of variable or function names. Keywords
+/// are only recognized as whole words, so `assets` or `iffy` stay variables.
+const KEYWORDS: [(&str, LexerToken); 15] = [
+    ("as", LexerToken::As),
+    ("block", LexerToken::Block),
+    ("else", LexerToken::Else),
+    ("elseif", LexerToken::ElseIf),
+    ("endblock", LexerToken::EndBlock),
+    ("endforeach", LexerToken::EndForEach),
+    ("endif", LexerToken::EndIf),
+    ("extends", LexerToken::Extends),
+    ("false", LexerToken::False),
+    ("foreach", LexerToken::ForEach),
+    ("if", LexerToken::If),
+    ("include", LexerToken::Include),
+    ("null", LexerToken::Null),
+    ("true", LexerToken::True),
+    ("with", LexerToken::With),
+];
+
+/// Keyword token of a whole word, ignoring ASCII case unless `case_sensitive`.
+pub fn get_keyword(word: &str, case_sensitive: bool) -> Option<LexerToken> {
+    KEYWORDS
+        .iter()
+        .find(|(keyword, _)| {
+            if case_sensitive {
+                word == *keyword
+            } else {
+                word.eq_ignore_ascii_case(keyword)
+            }
+        })
+        .map(|(_, token)| token.clone())
+}
+
+/// Word of a keyword token.
+pub fn get_keyword_word(token: &LexerToken) -> Option<&'static str> {
+    KEYWORDS
+        .iter()
+        .find(|(_, keyword)| keyword == token)
+        .map(|(word, _)| *word)
+}
+
+/// Built-in lexer items, whose regular expressions are compiled only once
+/// for all environments.
 pub fn get_lexer_items() -> Vec<LexerTokenMatcher> {
+    static LEXER_ITEMS: OnceLock<Vec<LexerTokenMatcher>> = OnceLock::new();
+    LEXER_ITEMS.get_or_init(create_lexer_items).clone()
+}
+
+// Setup lexer patterns here
+fn create_lexer_items() -> Vec<LexerTokenMatcher> {
     let mut items: Vec<LexerTokenMatcher> = Vec::new();
 
     // Addition
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Addition,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("+".to_string()),
         state: LexerState::Code,
     });
 
     // AddOne
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::AddOne,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("++".to_string()),
         state: LexerState::Code,
     });
 
     // And
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::And,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("&&".to_string()),
         state: LexerState::Code,
     });
 
-    // As
-    items.push(LexerTokenMatcher {
-        logic: Box::new(
-            |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
-             length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
-             elements: &mut Vec<LexerElement>,
-             _state: &mut LexerState| {
-                elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
-                    token: LexerToken::As,
-                });
-            },
-        ),
-        pattern: LexerTokenMatchPattern::Literal("as".to_string()),
-        state: LexerState::Code,
-    });
-
     // Assign
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Assign,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("=".to_string()),
         state: LexerState::Code,
     });
 
-    // Call
+    // Call, or a keyword followed by a parenthesis once looked up in the keyword table
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
-                let variable_name = &buffer[(*char_index)..(char_index + *length - 1)];
+                let variable_name = &buffer[(*byte_index)..(byte_index + *length - 1)];
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length - 1),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length - 1),
                     token: LexerToken::Call(variable_name.to_string()),
                 });
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (char_index + *length - 1),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(byte_index + *length - 1, byte_index + *length),
                     token: LexerToken::OpenParenthesis,
                 });
             },
         ),
-        pattern: LexerTokenMatchPattern::Regex(r"[a-zA-Z][a-zA-Z0-9_]*\(".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::regex(r"\p{XID_Start}\p{XID_Continue}*\(").unwrap(),
         state: LexerState::Code,
     });
 
     // CloseCurlyBracket
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::CloseCurlyBracket,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("}".to_string()),
         state: LexerState::Code,
     });
 
     // CloseParenthesis
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::CloseParenthesis,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal(")".to_string()),
         state: LexerState::Code,
     });
 
+    // CloseSquareBracket
+    items.push(LexerTokenMatcher {
+        logic: Arc::new(
+            |_buffer: &str,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
+             length: &mut usize,
+             elements: &mut Vec<LexerElement>,
+             _state: &mut LexerState| {
+                elements.push(LexerElement {
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
+                    token: LexerToken::CloseSquareBracket,
+                });
+            },
+        ),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::Literal("]".to_string()),
+        state: LexerState::Code,
+    });
+
     // CloseTag
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::CloseTag,
                 });
                 (*state) = LexerState::Initial;
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal(" %}".to_string()),
         state: LexerState::Code,
     });
 
     // CloseTagWithEcho
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::CloseTagWithEcho,
                 });
                 (*state) = LexerState::Initial;
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal(" }}".to_string()),
         state: LexerState::Code,
     });
 
     // Comma
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Comma,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal(",".to_string()),
         state: LexerState::Code,
     });
 
     // Division
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Division,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("/".to_string()),
         state: LexerState::Code,
     });
 
-    // DoubleQuotedString
+    // Dot
     items.push(LexerTokenMatcher {
-        logic: Box::new(
-            |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
-             length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
-             elements: &mut Vec<LexerElement>,
-             _state: &mut LexerState| {
-                // Search string forward until next un-escaped double quote
-                let index_start: usize = *char_index + 1;
-                let mut index: usize = 0;
-                let mut index_end: Option<usize> = None;
-                let mut previous_was_escape = false;
-                for character in buffer.chars() {
-                    if index > index_start {
-                        if previous_was_escape {
-                            previous_was_escape = false;
-                        } else {
-                            if character == '\\' {
-                                previous_was_escape = true;
-                            } else if character == '"' {
-                                index_end = Some(index);
-                                break;
-                            } else if character == '\n' {
-                                *line_end = *line_end + 1;
-                            }
-                        }
-                    }
-                    index = index + 1;
-                }
-
-                if index_end.is_some() {
-                    let contents = &buffer[(char_index + 1)..(index_end.unwrap())];
-                    *length = index_end.unwrap() - char_index;
-                    elements.push(LexerElement {
-                        position: LexerPosition {
-                            char_end: index_end.unwrap(),
-                            char_start: (*char_index),
-                            line_end: (*line_end),
-                            line_start: (*line_start),
-                        },
-                        token: LexerToken::DoubleQuotedString(contents.to_string()),
-                    });
-                }
-            },
-        ),
-        pattern: LexerTokenMatchPattern::Literal("\"".to_string()),
-        state: LexerState::Code,
-    });
-
-    // EndForEach
-    items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
-                    token: LexerToken::EndForEach,
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
+                    token: LexerToken::Dot,
                 });
             },
         ),
-        pattern: LexerTokenMatchPattern::Literal("endforeach".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::Literal(".".to_string()),
         state: LexerState::Code,
     });
 
-    // Else
+    // DoubleArrow
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
-                    token: LexerToken::Else,
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
+                    token: LexerToken::DoubleArrow,
                 });
             },
         ),
-        pattern: LexerTokenMatchPattern::Literal("else".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::Literal("=>".to_string()),
         state: LexerState::Code,
     });
 
-    // ElseIf
+    // DoubleQuotedString
     items.push(LexerTokenMatcher {
-        logic: Box::new(
-            |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+        logic: Arc::new(
+            |buffer: &str,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
-                elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
-                    token: LexerToken::ElseIf,
-                });
-            },
-        ),
-        pattern: LexerTokenMatchPattern::Literal("elseif".to_string()),
-        state: LexerState::Code,
-    });
+                // Search string forward until next un-escaped double quote
+                let index_start: usize = *byte_index + 1;
+                let mut index_end: Option<usize> = None;
+                let mut previous_was_escape = false;
+                for (offset, character) in buffer[index_start..].char_indices() {
+                    if previous_was_escape {
+                        previous_was_escape = false;
+                    } else if character == '\\' {
+                        previous_was_escape = true;
+                    } else if character == '"' {
+                        index_end = Some(index_start + offset);
+                        break;
+                    }
+                }
 
-    // EndIf
-    items.push(LexerTokenMatcher {
-        logic: Box::new(
-            |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
-             length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
-             elements: &mut Vec<LexerElement>,
-             _state: &mut LexerState| {
-                elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
-                    token: LexerToken::EndIf,
-                });
+                if let Some(index_end) = index_end {
+                    let contents = &buffer[(byte_index + 1)..index_end];
+                    *length = index_end - byte_index + 1;
+                    elements.push(LexerElement {
+                        position: LexerPosition::new(*byte_index, index_end + 1),
+                        token: LexerToken::DoubleQuotedString(contents.to_string()),
+                    });
+                } else {
+                    // An unterminated string breaks the tag, its text isn't code
+                    elements.push(LexerElement {
+                        position: LexerPosition::new(*byte_index, byte_index + 1),
+                        token: LexerToken::Unrecognized('\"'),
+                    });
+                }
             },
         ),
-        pattern: LexerTokenMatchPattern::Literal("endif".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::Literal("\"".to_string()),
         state: LexerState::Code,
     });
 
     // Equals
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Equals,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("==".to_string()),
         state: LexerState::Code,
     });
 
     // Float
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
-                let float_value = &buffer[(*char_index)..(char_index + *length)];
+                let float_value = &buffer[(*byte_index)..(byte_index + *length)];
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Float(float_value.to_string()),
                 });
             },
         ),
-        pattern: LexerTokenMatchPattern::Regex(r"[0-9]\.[0-9]+".to_string()),
-        state: LexerState::Code,
-    });
-
-    // ForEach
-    items.push(LexerTokenMatcher {
-        logic: Box::new(
-            |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
-             length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
-             elements: &mut Vec<LexerElement>,
-             _state: &mut LexerState| {
-                elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
-                    token: LexerToken::ForEach,
-                });
-            },
-        ),
-        pattern: LexerTokenMatchPattern::Literal("foreach".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::regex(r"[0-9]+\.[0-9]+").unwrap(),
         state: LexerState::Code,
     });
 
     // GreaterThan
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::GreaterThan,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal(">".to_string()),
         state: LexerState::Code,
     });
 
     // GreaterOrEqualThan
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::GreaterOrEqualThan,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal(">=".to_string()),
         state: LexerState::Code,
     });
 
-    // If
-    items.push(LexerTokenMatcher {
-        logic: Box::new(
-            |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
-             length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
-             elements: &mut Vec<LexerElement>,
-             _state: &mut LexerState| {
-                elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
-                    token: LexerToken::If,
-                });
-            },
-        ),
-        pattern: LexerTokenMatchPattern::Literal("if".to_string()),
-        state: LexerState::Code,
-    });
-
     // Integer
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
-                let integer_value = &buffer[(*char_index)..(char_index + *length)];
+                let integer_value = &buffer[(*byte_index)..(byte_index + *length)];
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Integer(integer_value.to_string()),
                 });
             },
         ),
-        pattern: LexerTokenMatchPattern::Regex(r"[1-9][0-9]*".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::regex(r"[0-9]+").unwrap(),
         state: LexerState::Code,
     });
 
     // LesserThan
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::LesserThan,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("<".to_string()),
         state: LexerState::Code,
     });
 
     // LesserOrEqualThan
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::LesserOrEqualThan,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("<=".to_string()),
         state: LexerState::Code,
     });
 
     // LesserOrGreatherThan
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::LesserOrGreaterThan,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("<>".to_string()),
         state: LexerState::Code,
     });
 
     // Multiplication
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Multiplication,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("*".to_string()),
         state: LexerState::Code,
     });
 
     // Negation
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Negation,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("!".to_string()),
         state: LexerState::Code,
     });
 
     // OpenCurlyBracket
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::OpenCurlyBracket,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("{".to_string()),
         state: LexerState::Code,
     });
 
     // OpenParenthesis
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::OpenParenthesis,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("(".to_string()),
         state: LexerState::Code,
     });
 
+    // OpenSquareBracket
+    items.push(LexerTokenMatcher {
+        logic: Arc::new(
+            |_buffer: &str,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
+             length: &mut usize,
+             elements: &mut Vec<LexerElement>,
+             _state: &mut LexerState| {
+                elements.push(LexerElement {
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
+                    token: LexerToken::OpenSquareBracket,
+                });
+            },
+        ),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::Literal("[".to_string()),
+        state: LexerState::Code,
+    });
+
     // OpenTag
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              state: &mut LexerState| {
                 // Only add inline if it's not empty
-                if char_end < char_index {
-                    let new_buffer: &str = &buffer[*char_end..*char_index];
+                if *byte_end < *byte_index {
+                    let new_buffer: &str = &buffer[*byte_end..*byte_index];
                     elements.push(LexerElement {
-                        position: LexerPosition {
-                            char_end: *char_index,
-                            char_start: *char_end,
-                            line_end: *line_end,
-                            line_start: *line_start,
-                        },
+                        position: LexerPosition::new(*byte_end, *byte_index),
                         token: LexerToken::Inline(new_buffer.to_string()),
                     });
                 }
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::OpenTag,
                 });
                 (*state) = LexerState::Code;
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("{% ".to_string()),
         state: LexerState::Initial,
     });
 
     // OpenTagWithEcho
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              state: &mut LexerState| {
                 // Only add inline if it's not empty
-                if *char_end < *char_index {
-                    let new_buffer: &str = &buffer[*char_end..*char_index];
+                if *byte_end < *byte_index {
+                    let new_buffer: &str = &buffer[*byte_end..*byte_index];
                     elements.push(LexerElement {
-                        position: LexerPosition {
-                            char_end: *char_index,
-                            char_start: *char_end,
-                            line_end: *line_end,
-                            line_start: *line_start,
-                        },
+                        position: LexerPosition::new(*byte_end, *byte_index),
                         token: LexerToken::Inline(new_buffer.to_string()),
                     });
                 }
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (*char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, *byte_index + *length),
                     token: LexerToken::OpenTagWithEcho,
                 });
                 (*state) = LexerState::Code;
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("{{ ".to_string()),
         state: LexerState::Initial,
     });
 
     // Or
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Or,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("||".to_string()),
         state: LexerState::Code,
     });
 
+    // Pipe
+    items.push(LexerTokenMatcher {
+        logic: Arc::new(
+            |_buffer: &str,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
+             length: &mut usize,
+             elements: &mut Vec<LexerElement>,
+             _state: &mut LexerState| {
+                elements.push(LexerElement {
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
+                    token: LexerToken::Pipe,
+                });
+            },
+        ),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::Literal("|".to_string()),
+        state: LexerState::Code,
+    });
+
     // Semicolon
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Semicolon,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal(";".to_string()),
         state: LexerState::Code,
     });
 
     // SingleQuotedString
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 // Search string forward until next un-escaped single quote
-                let index_start: usize = *char_index + 1;
-                let mut index: usize = 0;
+                let index_start: usize = *byte_index + 1;
                 let mut index_end: Option<usize> = None;
                 let mut previous_was_escape = false;
-                for character in buffer.chars() {
-                    if index > index_start {
-                        if previous_was_escape {
-                            previous_was_escape = false;
-                        } else {
-                            if character == '\\' {
-                                previous_was_escape = true;
-                            } else if character == '\'' {
-                                index_end = Some(index);
-                                break;
-                            } else if character == '\n' {
-                                *line_end = *line_end + 1;
-                            }
-                        }
+                for (offset, character) in buffer[index_start..].char_indices() {
+                    if previous_was_escape {
+                        previous_was_escape = false;
+                    } else if character == '\\' {
+                        previous_was_escape = true;
+                    } else if character == '\'' {
+                        index_end = Some(index_start + offset);
+                        break;
                     }
-                    index = index + 1;
                 }
 
-                if index_end.is_some() {
-                    let contents = &buffer[(char_index + 1)..(index_end.unwrap())];
-                    *length = index_end.unwrap() - char_index;
+                if let Some(index_end) = index_end {
+                    let contents = &buffer[(byte_index + 1)..index_end];
+                    *length = index_end - byte_index + 1;
                     elements.push(LexerElement {
-                        position: LexerPosition {
-                            char_end: index_end.unwrap(),
-                            char_start: (*char_index),
-                            line_end: (*line_end),
-                            line_start: (*line_start),
-                        },
+                        position: LexerPosition::new(*byte_index, index_end + 1),
                         token: LexerToken::SingleQuotedString(contents.to_string()),
                     });
+                } else {
+                    // An unterminated string breaks the tag, its text isn't code
+                    elements.push(LexerElement {
+                        position: LexerPosition::new(*byte_index, byte_index + 1),
+                        token: LexerToken::Unrecognized('\''),
+                    });
                 }
             },
         ),
-        pattern: LexerTokenMatchPattern::Literal("\"".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::Literal("'".to_string()),
         state: LexerState::Code,
     });
 
     // Subtraction
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Subtraction,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("-".to_string()),
         state: LexerState::Code,
     });
 
     // SubtractOne
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |_buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::SubtractOne,
                 });
             },
         ),
+        next_state: None,
         pattern: LexerTokenMatchPattern::Literal("--".to_string()),
         state: LexerState::Code,
     });
 
-    // Variable
+    // Variable, or a keyword once looked up in the keyword table
     items.push(LexerTokenMatcher {
-        logic: Box::new(
+        logic: Arc::new(
             |buffer: &str,
-             char_index: &usize,
-             _char_start: &usize,
-             _char_end: &usize,
+             byte_index: &usize,
+             _byte_start: &usize,
+             _byte_end: &usize,
              length: &mut usize,
-             _line_index: &usize,
-             line_start: &usize,
-             line_end: &mut usize,
              elements: &mut Vec<LexerElement>,
              _state: &mut LexerState| {
-                let variable_name = &buffer[(*char_index)..(char_index + *length)];
+                let variable_name = &buffer[(*byte_index)..(byte_index + *length)];
                 elements.push(LexerElement {
-                    position: LexerPosition {
-                        char_end: (char_index + *length),
-                        char_start: (*char_index),
-                        line_end: (*line_end),
-                        line_start: (*line_start),
-                    },
+                    position: LexerPosition::new(*byte_index, byte_index + *length),
                     token: LexerToken::Variable(variable_name.to_string()),
                 });
             },
         ),
-        pattern: LexerTokenMatchPattern::Regex(r"[a-zA-Z][a-zA-Z0-9_]*".to_string()),
+        next_state: None,
+        pattern: LexerTokenMatchPattern::regex(r"\p{XID_Start}\p{XID_Continue}*").unwrap(),
         state: LexerState::Code,
     });
 